@@ -0,0 +1,375 @@
+// SPDX-License-Identifier: MIT
+// Copyright 2024 brog Authors
+
+//! A brog agent for embedding in a supervisor.
+//!
+//! An [`Agent`] owns its [`BrogConfig`], one `reqwest::Client` and the
+//! [`BootcDriver`], and is reused for every reconciliation:
+//!
+//! ```no_run
+//! # async fn example() -> Result<(), anyhow::Error> {
+//! let config = brog::BrogConfig::builder("https://example.com/brog.yaml")
+//!     .config_path("/var/lib/brog")
+//!     .build()?;
+//! let agent = brog::Agent::new(config)?;
+//! let outcome = agent.reconcile().await?;
+//! println!("{}", outcome);
+//! # Ok(())
+//! # }
+//! ```
+
+use crate::{
+    bootc_with_retries,
+    driver::BootcDriver,
+    error::BrogError,
+    health::{self, HealthOutcome},
+    metrics::Metrics,
+    moved_since_staged,
+    outcome::{Outcome, ReconcileOutcome},
+    reconcile, registry,
+    report::{self, Report},
+    settings::BrogConfig,
+    signer::Signer,
+    Change, Plan, ProcessOptions, State, StateStore,
+};
+use std::{
+    fs,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+use tokio::sync::Mutex;
+use tracing::{info, warn};
+
+/// Clones share the HTTP client, the driver and the lock that keeps their
+/// reconciliations, `apply_staged` and health checks from overlapping.
+#[derive(Debug, Clone)]
+pub struct Agent {
+    config: BrogConfig,
+    options: ProcessOptions,
+    client: reqwest::Client,
+    driver: Arc<dyn BootcDriver>,
+    busy: Arc<Mutex<()>>,
+}
+
+impl Agent {
+    /// Validates `config` and prepares the HTTP client and bootc driver.
+    pub fn new(config: BrogConfig) -> Result<Self, BrogError> {
+        config.validate()?;
+        let options = config.options();
+        let client = reqwest::Client::builder()
+            .timeout(options.fetch_timeout)
+            .build()
+            .map_err(|e| BrogError::config(format!("Cannot create HTTP client: {}", e)))?;
+        let driver = options.driver(&config.bin_path);
+        Ok(Agent {
+            config,
+            options,
+            client,
+            driver,
+            busy: Arc::new(Mutex::new(())),
+        })
+    }
+
+    /// Runs bootc through `driver`, e.g. a [`crate::FakeDriver`] in tests.
+    pub fn with_driver(mut self, driver: Arc<dyn BootcDriver>) -> Self {
+        self.driver = self.options.metered(driver);
+        self
+    }
+
+    /// Records fetches, bootc commands and outcomes into `metrics`, starting
+    /// from the values in the state file.
+    pub fn with_metrics(mut self, metrics: Arc<Metrics>) -> Self {
+        if let Ok(state) = self.store().load() {
+            metrics.observe_state(&state);
+        }
+        self.options.metrics = Some(metrics);
+        self.driver = self.options.metered(self.driver);
+        self
+    }
+
+    /// Resolves tags for `resolveDigest` with `registry`.
+    pub fn with_registry(mut self, registry: Arc<dyn registry::RegistryClient>) -> Self {
+        self.options.registry = Some(registry);
+        self
+    }
+
+    pub fn config(&self) -> &BrogConfig {
+        &self.config
+    }
+
+    pub fn driver(&self) -> &dyn BootcDriver {
+        self.driver.as_ref()
+    }
+
+    fn store(&self) -> StateStore {
+        let store = StateStore::new(&self.config.config_path);
+        if self.options.dry_run {
+            store.read_only()
+        } else {
+            store
+        }
+    }
+
+    /// Fetches the config and brings the host in line with it, recording
+    /// the outcome in the state and sending a report when configured.
+    ///
+    /// A target refused by a local rule is an [`Outcome::Rejected`] rather
+    /// than an error. Waits for a reconciliation already running on this
+    /// agent to finish first.
+    pub async fn reconcile(&self) -> Result<ReconcileOutcome, BrogError> {
+        let _busy = self.busy.lock().await;
+        self.reconcile_now().await
+    }
+
+    /// Like [`Agent::reconcile`], but returns `None` at once when a
+    /// reconciliation is already running, as a scheduled tick should.
+    pub async fn try_reconcile(&self) -> Result<Option<ReconcileOutcome>, BrogError> {
+        match self.busy.try_lock() {
+            Ok(_busy) => self.reconcile_now().await.map(Some),
+            Err(_) => Ok(None),
+        }
+    }
+
+    #[tracing::instrument(name = "execute process", skip(self), fields(endpoint = %self.config.endpoint))]
+    async fn reconcile_now(&self) -> Result<ReconcileOutcome, BrogError> {
+        if self.config.endpoint.is_empty() {
+            return Err(BrogError::config("ENTRYPOINT cannot be empty"));
+        }
+        let options = &self.options;
+        let store = self.store();
+        let signer = self.signer();
+        let started_at = chrono::Utc::now();
+        let started = Instant::now();
+        let mut state = store.load()?;
+        let decision = match reconcile(
+            &self.config.endpoint,
+            self.driver.as_ref(),
+            &self.client,
+            &signer,
+            &store,
+            &mut state,
+            options,
+        )
+        .await
+        .map_err(BrogError::from)
+        {
+            Err(BrogError::PolicyRejected {
+                image,
+                policy,
+                reason,
+            }) => {
+                let host = self.driver.status().await.ok();
+                let plan = Plan {
+                    from: host
+                        .as_ref()
+                        .and_then(|h| h.booted_image())
+                        .map(|b| format!("{}@{}", b.image.image, b.image_digest)),
+                    to: image,
+                    switch: false,
+                    apply: false,
+                    reason: reason.clone(),
+                    dry_run: options.dry_run,
+                };
+                Ok((Outcome::Rejected { policy, reason }, plan))
+            }
+            decision => decision,
+        };
+        // Everything the reconciliation changed is saved at once.
+        let now = chrono::Utc::now();
+        match &decision {
+            Ok((Outcome::Rejected { reason, .. }, _)) => {
+                state.last_failure = Some(now);
+                state.last_error = Some(reason.clone());
+            }
+            Ok(_) => state.last_success = Some(now),
+            Err(e) => {
+                state.last_failure = Some(now);
+                state.last_error = Some(e.to_string());
+            }
+        }
+        store.save(&state)?;
+        let result = decision.map(|(outcome, plan)| {
+            ReconcileOutcome::new(
+                outcome,
+                plan,
+                state.commit.clone(),
+                started_at,
+                started.elapsed(),
+            )
+        });
+        self.publish(&result, &state, signer, started.elapsed())
+            .await;
+        if let Ok(outcome) = &result {
+            if outcome.dry_run {
+                info!("Dry run: {}", outcome);
+            }
+        }
+        result
+    }
+
+    /// Reboots into the deployment staged by an earlier reconciliation.
+    ///
+    /// This is the explicit trigger for [`BrogConfig::stage_only`] and fails
+    /// when nothing is staged, the staged image is not the current target or
+    /// its tag has moved since it was staged. A moved tag is left to the next
+    /// reconciliation, which checks the new content before booting it.
+    pub async fn apply_staged(&self) -> Result<Plan, BrogError> {
+        let _busy = self.busy.lock().await;
+        let store = self.store();
+        let mut state = store.load()?;
+        let host = self.driver.status().await?;
+        let staged = match host.staged_image() {
+            Some(staged) => staged,
+            None => {
+                return Err(BrogError::NotStaged {
+                    staged: None,
+                    target: state.target_image.clone(),
+                })
+            }
+        };
+        let image = match &state.target_image {
+            Some(target) if !staged.matches(target) => {
+                return Err(BrogError::NotStaged {
+                    staged: Some(staged.image.image.clone()),
+                    target: Some(target.clone()),
+                })
+            }
+            Some(target) => target.clone(),
+            None => staged.image.image.clone(),
+        };
+        if let Some(digest) = moved_since_staged(self.driver.as_ref(), staged).await? {
+            return Err(BrogError::NotStaged {
+                staged: Some(format!("{}@{}", staged.image.image, staged.image_digest)),
+                target: Some(format!("{}@{}", image, digest)),
+            });
+        }
+        let plan = Plan {
+            from: host
+                .booted_image()
+                .map(|b| format!("{}@{}", b.image.image, b.image_digest)),
+            to: image.clone(),
+            switch: true,
+            apply: true,
+            reason: "applying staged deployment".to_owned(),
+            dry_run: self.options.dry_run,
+        };
+        if self.options.dry_run {
+            info!("Dry run: {}", plan);
+            return Ok(plan);
+        }
+        let result = bootc_with_retries(
+            self.driver.as_ref(),
+            Change::Upgrade,
+            true,
+            &image,
+            "applied",
+            1,
+            &store,
+            &mut state,
+        )
+        .await;
+        store.save(&state)?;
+        result?;
+        Ok(plan)
+    }
+
+    /// Runs the health checks of a pending update, see
+    /// [`health::verify_pending`].
+    ///
+    /// A rollback is counted and reported like a reconciliation whose
+    /// outcome is [`Outcome::RolledBack`].
+    pub async fn verify_health(&self) -> Result<HealthOutcome, BrogError> {
+        let _busy = self.busy.lock().await;
+        let started_at = chrono::Utc::now();
+        let started = Instant::now();
+        let outcome = health::verify(
+            &self.config.config_path,
+            &self.config.bin_path,
+            self.driver.as_ref(),
+            &self.client,
+        )
+        .await?;
+        let state = match self.store().load() {
+            Ok(state) => state,
+            Err(e) => {
+                warn!("Cannot read the state after the health checks: {}", e);
+                return Ok(outcome);
+            }
+        };
+        match &outcome {
+            HealthOutcome::RolledBack {
+                image,
+                rolled_back_to,
+                reason,
+            } => {
+                let plan = Plan {
+                    from: Some(image.clone()),
+                    to: rolled_back_to.clone().unwrap_or_else(|| image.clone()),
+                    switch: false,
+                    apply: false,
+                    reason: reason.clone(),
+                    dry_run: false,
+                };
+                let result = Ok(ReconcileOutcome::new(
+                    Outcome::RolledBack,
+                    plan,
+                    state.commit.clone(),
+                    started_at,
+                    started.elapsed(),
+                ));
+                self.publish(&result, &state, self.signer(), started.elapsed())
+                    .await;
+            }
+            _ => {
+                if let Some(metrics) = &self.options.metrics {
+                    metrics.observe_state(&state);
+                }
+            }
+        }
+        Ok(outcome)
+    }
+
+    fn signer(&self) -> Signer {
+        Signer::new(
+            &self.config.service_key,
+            &self.config.service_secret,
+            &self.config.service_name,
+        )
+        .with_region(&self.options.region)
+    }
+
+    /// Records `result` in the metrics and sends it as a report.
+    async fn publish(
+        &self,
+        result: &Result<ReconcileOutcome, BrogError>,
+        state: &State,
+        signer: Signer,
+        duration: Duration,
+    ) {
+        let options = &self.options;
+        let report_url = options.report_url.as_ref().filter(|_| !options.dry_run);
+        let host = match (report_url, &options.metrics) {
+            (None, None) => None,
+            _ => self.driver.status().await.ok(),
+        };
+        if let Some(metrics) = &options.metrics {
+            metrics.reconciled(result, state, host.as_ref());
+        }
+        if let Some(url) = report_url {
+            let machine_id = fs::read_to_string(&options.machine_id_path).unwrap_or_default();
+            let hostname = fs::read_to_string(&options.hostname_path).unwrap_or_default();
+            let report = Report::new(
+                result,
+                state,
+                host.as_ref(),
+                &machine_id,
+                &hostname,
+                duration,
+            );
+            let signer = signer.with_identity(&report.machine_id, &report.hostname);
+            if let Err(e) = report::send(&self.client, url, &signer, &report).await {
+                warn!("Could not send report: {}", e);
+            }
+        }
+    }
+}
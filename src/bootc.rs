@@ -0,0 +1,134 @@
+// SPDX-License-Identifier: MIT
+// Copyright 2024 brog Authors
+
+//! Model of the `BootcHost` document printed by `bootc status --format yaml`.
+//!
+//! Only the fields brog uses are modelled. Everything is optional so that a
+//! newer bootc adding or dropping fields does not stop brog from working.
+
+use crate::error::BrogError;
+use serde::{Deserialize, Serialize};
+
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default, rename_all = "camelCase")]
+pub struct BootcHost {
+    pub api_version: String,
+    pub kind: String,
+    pub spec: HostSpec,
+    pub status: HostStatus,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default, rename_all = "camelCase")]
+pub struct HostSpec {
+    pub image: Option<ImageReference>,
+    pub boot_order: Option<String>,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default, rename_all = "camelCase")]
+pub struct ImageReference {
+    pub image: String,
+    pub transport: String,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default, rename_all = "camelCase")]
+pub struct HostStatus {
+    pub staged: Option<BootEntry>,
+    pub booted: Option<BootEntry>,
+    pub rollback: Option<BootEntry>,
+    pub rollback_queued: bool,
+    #[serde(rename = "type")]
+    pub host_type: Option<String>,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default, rename_all = "camelCase")]
+pub struct BootEntry {
+    pub image: Option<ImageStatus>,
+    pub cached_update: Option<ImageStatus>,
+    pub incompatible: bool,
+    pub pinned: bool,
+    pub store: Option<String>,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default, rename_all = "camelCase")]
+pub struct ImageStatus {
+    pub image: ImageReference,
+    pub version: Option<String>,
+    pub timestamp: Option<String>,
+    pub image_digest: String,
+}
+
+impl BootcHost {
+    pub fn from_yaml(text: &str) -> Result<Self, BrogError> {
+        serde_yaml::from_str(text).map_err(|e| BrogError::parse("bootc status", e))
+    }
+
+    pub fn booted_image(&self) -> Option<&ImageStatus> {
+        self.status.booted.as_ref().and_then(|b| b.image.as_ref())
+    }
+
+    pub fn staged_image(&self) -> Option<&ImageStatus> {
+        self.status.staged.as_ref().and_then(|b| b.image.as_ref())
+    }
+
+    pub fn rollback_image(&self) -> Option<&ImageStatus> {
+        self.status.rollback.as_ref().and_then(|b| b.image.as_ref())
+    }
+
+    /// The version of `target` if bootc knows it from a staged, rollback or
+    /// cached update deployment. With a `digest`, only a deployment with that
+    /// digest counts. When `target` is the booted image reference the
+    /// rollback deployment holds older content of the same tag, so it is not
+    /// asked.
+    pub fn version_of(&self, target: &str, digest: Option<&str>) -> Option<&str> {
+        let cached = self
+            .status
+            .booted
+            .as_ref()
+            .and_then(|b| b.cached_update.as_ref());
+        let rollback = self
+            .rollback_image()
+            .filter(|_| !self.booted_image().is_some_and(|b| b.matches(target)));
+        [self.staged_image(), rollback, cached]
+            .into_iter()
+            .flatten()
+            .find(|status| {
+                status.matches(target) && digest.is_none_or(|d| d == status.image_digest)
+            })
+            .and_then(|status| status.version.as_deref())
+    }
+
+    /// True when the host is booted into, or has staged, `target`.
+    pub fn is_on(&self, target: &str) -> bool {
+        [self.booted_image(), self.staged_image()]
+            .into_iter()
+            .flatten()
+            .any(|status| status.matches(target))
+    }
+}
+
+impl ImageStatus {
+    /// Compares a brog.yaml image against this deployment. References pinned
+    /// with `@sha256:` are compared by repository and digest, ignoring any
+    /// tag next to the digest, tags by name.
+    pub fn matches(&self, target: &str) -> bool {
+        match target.split_once('@') {
+            Some((repository, digest)) => {
+                self.image_digest == digest && strip_tag(&self.image.image) == strip_tag(repository)
+            }
+            None => self.image.image == target,
+        }
+    }
+}
+
+fn strip_tag(image: &str) -> &str {
+    let image = image.split_once('@').map_or(image, |(name, _)| name);
+    match image.rfind(':') {
+        Some(i) if !image[i..].contains('/') => &image[..i],
+        _ => image,
+    }
+}
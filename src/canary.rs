@@ -0,0 +1,63 @@
+// SPDX-License-Identifier: MIT
+// Copyright 2024 brog Authors
+
+//! Staged rollout driven by `canarySchedule` and `interval`.
+//!
+//! Every device is placed in a stable bucket between 0 and 99 derived from
+//! its machine-id. A rollout starts the first time a device sees a new image
+//! and moves to the next stage of the schedule every `interval` minutes.
+//! A device applies the image once the current stage percentage is greater
+//! than its bucket. After the last stage the whole estate is covered.
+
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use tracing::debug;
+
+/// Returns the stable bucket (0-99) for a machine-id.
+pub fn machine_bucket(machineid: &str) -> u8 {
+    let digest = Sha256::digest(machineid.trim().as_bytes());
+    let mut bytes = [0u8; 8];
+    bytes.copy_from_slice(&digest[..8]);
+    (u64::from_be_bytes(bytes) % 100) as u8
+}
+
+/// The percentage of devices covered `elapsed_minutes` into a rollout.
+/// Without an `interval` the rollout never leaves the first stage.
+pub fn current_percent(schedule: &[u8], interval: u64, elapsed_minutes: i64) -> u8 {
+    match (schedule.first(), interval) {
+        (None, _) => return 100,
+        (Some(first), 0) => return *first,
+        _ => {}
+    }
+    let stage = (elapsed_minutes.max(0) as u64 / interval) as usize;
+    schedule.get(stage).copied().unwrap_or(100)
+}
+
+/// The rollout of a single image as first seen by this device.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Rollout {
+    pub image: String,
+    pub started: DateTime<Utc>,
+}
+
+impl Rollout {
+    /// Continues `previous` if it is for the same image, otherwise starts a
+    /// new rollout at `now`.
+    pub fn for_image(previous: Option<&Rollout>, image: &str, now: DateTime<Utc>) -> Self {
+        match previous {
+            Some(rollout) if rollout.image == image => rollout.clone(),
+            _ => {
+                debug!("Starting rollout of {}", image);
+                Rollout {
+                    image: image.to_owned(),
+                    started: now,
+                }
+            }
+        }
+    }
+
+    pub fn percent(&self, schedule: &[u8], interval: u64, now: DateTime<Utc>) -> u8 {
+        current_percent(schedule, interval, (now - self.started).num_minutes())
+    }
+}
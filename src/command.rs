@@ -0,0 +1,198 @@
+// SPDX-License-Identifier: MIT
+// Copyright 2024 brog Authors
+
+//! Running bootc and capturing what it printed.
+//!
+//! A command succeeds when it exits with status 0. bootc prints progress and
+//! warnings on stderr, so stderr is kept as diagnostic text rather than
+//! treated as a failure.
+//!
+//! [`run_command_async`] runs on tokio with a timeout and kills bootc when it
+//! expires or the future is dropped. The blocking [`run_command`] is kept for
+//! callers outside a runtime.
+
+use std::{
+    fmt, io,
+    process::{Command, ExitStatus, Output, Stdio},
+    time::{Duration, Instant},
+};
+use tracing::debug;
+
+/// The result of a command that exited successfully.
+#[derive(Debug, Clone)]
+pub struct CommandOutput {
+    pub status: ExitStatus,
+    pub stdout: String,
+    pub stderr: String,
+    pub duration: Duration,
+}
+
+#[derive(Debug)]
+pub enum CommandError {
+    /// The program could not be started, e.g. it is not on the `PATH`.
+    Spawn {
+        program: String,
+        args: Vec<String>,
+        source: io::Error,
+    },
+    /// The program ran and exited with a non-zero status.
+    Failed {
+        program: String,
+        args: Vec<String>,
+        status: ExitStatus,
+        stdout: String,
+        stderr: String,
+        duration: Duration,
+    },
+    /// The program did not finish within the timeout and was killed.
+    TimedOut {
+        program: String,
+        args: Vec<String>,
+        timeout: Duration,
+    },
+}
+
+impl CommandError {
+    /// What the command printed on stderr, empty when it did not run.
+    pub fn stderr(&self) -> &str {
+        match self {
+            CommandError::Failed { stderr, .. } => stderr,
+            _ => "",
+        }
+    }
+
+    /// What the command printed on stdout, empty when it did not run.
+    pub fn stdout(&self) -> &str {
+        match self {
+            CommandError::Failed { stdout, .. } => stdout,
+            _ => "",
+        }
+    }
+}
+
+impl fmt::Display for CommandError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CommandError::Spawn {
+                program,
+                args,
+                source,
+            } => write!(f, "failed to execute {} {:?}: {}", program, args, source),
+            CommandError::Failed {
+                program,
+                args,
+                status,
+                stderr,
+                ..
+            } => {
+                write!(f, "{} {:?} failed with {}", program, args, status)?;
+                if !stderr.trim().is_empty() {
+                    write!(f, ": {}", stderr.trim())?;
+                }
+                Ok(())
+            }
+            CommandError::TimedOut {
+                program,
+                args,
+                timeout,
+            } => write!(f, "{} {:?} timed out after {:?}", program, args, timeout),
+        }
+    }
+}
+
+impl std::error::Error for CommandError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            CommandError::Spawn { source, .. } => Some(source),
+            _ => None,
+        }
+    }
+}
+
+/// Runs `bootc` with `args`, looking it up on `bin_path`.
+pub fn run_command(args: &[&str], bin_path: &str) -> Result<CommandOutput, CommandError> {
+    debug!("running bootc {:?} {:?}", args, bin_path);
+    let started = Instant::now();
+    let output = Command::new("bootc")
+        .env("PATH", bin_path)
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .args(args)
+        .spawn()
+        .and_then(|child| child.wait_with_output())
+        .map_err(|source| CommandError::Spawn {
+            program: "bootc".to_owned(),
+            args: owned(args),
+            source,
+        })?;
+    finish(args, output, started.elapsed())
+}
+
+/// Runs `bootc` with `args` without blocking the runtime. bootc is killed
+/// when it runs longer than `timeout` or the returned future is dropped.
+pub async fn run_command_async(
+    args: &[&str],
+    bin_path: &str,
+    timeout: Duration,
+) -> Result<CommandOutput, CommandError> {
+    debug!("running bootc {:?} {:?}", args, bin_path);
+    let started = Instant::now();
+    let child = tokio::process::Command::new("bootc")
+        .env("PATH", bin_path)
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .args(args)
+        .kill_on_drop(true)
+        .spawn()
+        .map_err(|source| CommandError::Spawn {
+            program: "bootc".to_owned(),
+            args: owned(args),
+            source,
+        })?;
+    let output = tokio::time::timeout(timeout, child.wait_with_output())
+        .await
+        .map_err(|_| CommandError::TimedOut {
+            program: "bootc".to_owned(),
+            args: owned(args),
+            timeout,
+        })?
+        .map_err(|source| CommandError::Spawn {
+            program: "bootc".to_owned(),
+            args: owned(args),
+            source,
+        })?;
+    finish(args, output, started.elapsed())
+}
+
+fn finish(
+    args: &[&str],
+    output: Output,
+    duration: Duration,
+) -> Result<CommandOutput, CommandError> {
+    let stdout = String::from_utf8_lossy(&output.stdout).into_owned();
+    let stderr = String::from_utf8_lossy(&output.stderr).into_owned();
+    debug!(
+        "bootc {:?} exited with {} after {:?}",
+        args, output.status, duration
+    );
+    if !output.status.success() {
+        return Err(CommandError::Failed {
+            program: "bootc".to_owned(),
+            args: owned(args),
+            status: output.status,
+            stdout,
+            stderr,
+            duration,
+        });
+    }
+    Ok(CommandOutput {
+        status: output.status,
+        stdout,
+        stderr,
+        duration,
+    })
+}
+
+fn owned(args: &[&str]) -> Vec<String> {
+    args.iter().map(|a| a.to_string()).collect()
+}
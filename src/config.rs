@@ -0,0 +1,326 @@
+// SPDX-License-Identifier: MIT
+// Copyright 2024 brog Authors
+
+//! Typed model of the `brog.yaml` document served from `ENDPOINT`.
+//!
+//! The same types are used by the agent and are exposed so that tooling can
+//! generate and validate configuration before it is published.
+
+use crate::{error::BrogError, health::HealthCheck, image::ImageRef, window::MaintenanceWindow};
+use serde::de::{self, Deserializer, MapAccess, SeqAccess, Visitor};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::fmt;
+
+/// The `apiVersion` written by [`BrogDocument::new`] and assumed when a
+/// document does not declare one.
+pub const API_VERSION: &str = "brog.mehal.tech/v1";
+
+/// The only `kind` brog understands.
+pub const KIND: &str = "BrogConfig";
+
+const SUPPORTED_API_VERSIONS: &[&str] = &[API_VERSION];
+
+/// A complete `brog.yaml` document.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct BrogDocument {
+    #[serde(default = "default_api_version")]
+    pub api_version: String,
+    #[serde(default = "default_kind")]
+    pub kind: String,
+    pub client_config: ClientConfigs,
+    /// Top level fields brog does not understand. They are reported by
+    /// [`BrogDocument::unknown_fields`] and never serialized.
+    #[serde(flatten, skip_serializing)]
+    pub unknown: BTreeMap<String, serde_yaml::Value>,
+}
+
+/// `clientConfig` may be written either as a single mapping or as a list of
+/// mappings. Only the first entry of a list is acted upon.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+#[serde(untagged)]
+pub enum ClientConfigs {
+    List(Vec<ClientConfig>),
+    Map(ClientConfig),
+}
+
+/// The settings for a single device group.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ClientConfig {
+    /// The container image reference passed to `bootc switch`.
+    #[serde(deserialize_with = "image_reference")]
+    pub image: String,
+    /// Percentages of the estate that receive the image at each stage.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub canary_schedule: Vec<u8>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub interval: Option<u64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub retrycount: Option<u32>,
+    /// Checks run on the first boot into `image`, see [`crate::health`].
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub health_check: Option<HealthCheck>,
+    /// How brog keeps a device on `image` up to date.
+    #[serde(default, skip_serializing_if = "UpdatePolicy::is_default")]
+    pub update_policy: UpdatePolicy,
+    /// Ask the registry which digest the image tag points at, so that a
+    /// moved tag is upgraded, see [`crate::registry`].
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub resolve_digest: bool,
+    /// Allow switching to an image with an older version than the booted one.
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub allow_downgrade: bool,
+    /// When the device may reboot into a new image, see [`crate::window`].
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub maintenance_windows: Vec<MaintenanceWindow>,
+    /// Fields brog does not understand. They are reported by
+    /// [`BrogDocument::unknown_fields`] and never serialized.
+    #[serde(flatten, skip_serializing)]
+    pub unknown: BTreeMap<String, serde_yaml::Value>,
+}
+
+/// What brog does when the booted image is already the configured reference.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum UpdatePolicy {
+    /// Switch when the reference differs. A moved tag is only noticed with
+    /// `resolveDigest`.
+    #[default]
+    Switch,
+    /// Also run `bootc upgrade --check` and upgrade in place when the tag
+    /// has new content.
+    Upgrade,
+    /// Never update in place, even when the tag moves.
+    Pinned,
+}
+
+impl UpdatePolicy {
+    fn is_default(&self) -> bool {
+        *self == UpdatePolicy::default()
+    }
+}
+
+fn default_api_version() -> String {
+    API_VERSION.to_owned()
+}
+
+fn default_kind() -> String {
+    KIND.to_owned()
+}
+
+impl BrogDocument {
+    pub fn new(client_config: ClientConfigs) -> Self {
+        BrogDocument {
+            api_version: default_api_version(),
+            kind: default_kind(),
+            client_config,
+            unknown: BTreeMap::new(),
+        }
+    }
+
+    /// Parses and validates a `brog.yaml` document.
+    ///
+    /// Errors name the YAML path of the offending value,
+    /// e.g. `clientConfig[0].image`.
+    pub fn from_yaml(text: &str) -> Result<Self, BrogError> {
+        let document: BrogDocument =
+            serde_yaml::from_str(text).map_err(|e| BrogError::parse("brog.yaml", e))?;
+        document.validate()?;
+        Ok(document)
+    }
+
+    pub fn to_yaml(&self) -> Result<String, BrogError> {
+        serde_yaml::to_string(self).map_err(|e| BrogError::Other(e.into()))
+    }
+
+    /// Checks the values serde cannot, such as the declared version and the
+    /// shape of the canary schedule.
+    pub fn validate(&self) -> Result<(), BrogError> {
+        if !SUPPORTED_API_VERSIONS.contains(&self.api_version.as_str()) {
+            return Err(BrogError::config(format!(
+                "Invalid brog.yaml: apiVersion: unsupported version {:?}, expected one of {:?}",
+                self.api_version, SUPPORTED_API_VERSIONS
+            )));
+        }
+        if self.kind != KIND {
+            return Err(BrogError::config(format!(
+                "Invalid brog.yaml: kind: unsupported kind {:?}, expected {:?}",
+                self.kind, KIND
+            )));
+        }
+        let configs = self.client_config.entries();
+        if configs.is_empty() {
+            return Err(BrogError::config(
+                "Invalid brog.yaml: clientConfig: at least one entry is required",
+            ));
+        }
+        for (path, config) in self.client_config.paths().iter().zip(configs) {
+            config
+                .validate(path)
+                .map_err(|e| BrogError::config(e.to_string()))?;
+        }
+        Ok(())
+    }
+
+    /// The config this device acts upon.
+    pub fn client(&self) -> Option<&ClientConfig> {
+        self.client_config.entries().first()
+    }
+
+    /// YAML paths of every field that was present but not understood.
+    pub fn unknown_fields(&self) -> Vec<String> {
+        let mut fields: Vec<String> = self.unknown.keys().cloned().collect();
+        for (path, config) in self
+            .client_config
+            .paths()
+            .iter()
+            .zip(self.client_config.entries())
+        {
+            for key in config.unknown.keys() {
+                fields.push(format!("{}.{}", path, key));
+            }
+        }
+        fields
+    }
+}
+
+impl ClientConfigs {
+    pub fn entries(&self) -> &[ClientConfig] {
+        match self {
+            ClientConfigs::List(list) => list,
+            ClientConfigs::Map(config) => std::slice::from_ref(config),
+        }
+    }
+
+    fn paths(&self) -> Vec<String> {
+        match self {
+            ClientConfigs::List(list) => (0..list.len())
+                .map(|i| format!("clientConfig[{}]", i))
+                .collect(),
+            ClientConfigs::Map(_) => vec!["clientConfig".to_owned()],
+        }
+    }
+}
+
+impl ClientConfig {
+    pub fn new(image: &str) -> Self {
+        ClientConfig {
+            image: image.to_owned(),
+            canary_schedule: vec![],
+            interval: None,
+            retrycount: None,
+            health_check: None,
+            update_policy: UpdatePolicy::default(),
+            resolve_digest: false,
+            allow_downgrade: false,
+            maintenance_windows: vec![],
+            unknown: BTreeMap::new(),
+        }
+    }
+
+    fn validate(&self, path: &str) -> Result<(), anyhow::Error> {
+        if self.image.trim().is_empty() || self.image.contains(char::is_whitespace) {
+            return Err(anyhow::anyhow!(
+                "Invalid brog.yaml: {}.image: {:?} is not a valid image reference",
+                path,
+                self.image
+            ));
+        }
+        if self.resolve_digest {
+            ImageRef::parse(&self.image)
+                .map_err(|e| anyhow::anyhow!("Invalid brog.yaml: {}.image: {}", path, e))?;
+        }
+        let mut previous = 0;
+        for (i, percent) in self.canary_schedule.iter().enumerate() {
+            if *percent == 0 || *percent > 100 || *percent <= previous {
+                return Err(anyhow::anyhow!(
+                    "Invalid brog.yaml: {}.canarySchedule[{}]: {} must be between 1 and 100 and greater than the previous stage",
+                    path,
+                    i,
+                    percent
+                ));
+            }
+            previous = *percent;
+        }
+        if !self.canary_schedule.is_empty() && self.interval.unwrap_or(0) == 0 {
+            return Err(anyhow::anyhow!(
+                "Invalid brog.yaml: {}.interval: a canarySchedule needs an interval of at least 1 minute",
+                path
+            ));
+        }
+        if let Some(check) = &self.health_check {
+            check.validate(path)?;
+        }
+        for (i, window) in self.maintenance_windows.iter().enumerate() {
+            window.validate(&format!("{}.maintenanceWindows[{}]", path, i))?;
+        }
+        Ok(())
+    }
+}
+
+impl<'de> Deserialize<'de> for ClientConfigs {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        struct ClientConfigsVisitor;
+
+        impl<'de> Visitor<'de> for ClientConfigsVisitor {
+            type Value = ClientConfigs;
+
+            fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+                formatter.write_str("a client config mapping or a list of client configs")
+            }
+
+            fn visit_seq<A>(self, mut seq: A) -> Result<Self::Value, A::Error>
+            where
+                A: SeqAccess<'de>,
+            {
+                let mut list = Vec::new();
+                while let Some(config) = seq.next_element()? {
+                    list.push(config);
+                }
+                Ok(ClientConfigs::List(list))
+            }
+
+            fn visit_map<A>(self, map: A) -> Result<Self::Value, A::Error>
+            where
+                A: MapAccess<'de>,
+            {
+                ClientConfig::deserialize(de::value::MapAccessDeserializer::new(map))
+                    .map(ClientConfigs::Map)
+            }
+        }
+
+        deserializer.deserialize_any(ClientConfigsVisitor)
+    }
+}
+
+// YAML resolves plain scalars such as `12345` to numbers, but a `String`
+// field would silently accept them. Image references must be written as
+// strings so that typos are caught rather than passed to bootc.
+fn image_reference<'de, D>(deserializer: D) -> Result<String, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    struct ImageVisitor;
+
+    impl<'de> Visitor<'de> for ImageVisitor {
+        type Value = String;
+
+        fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+            formatter.write_str("an image reference string")
+        }
+
+        fn visit_str<E>(self, v: &str) -> Result<Self::Value, E>
+        where
+            E: de::Error,
+        {
+            Ok(v.to_owned())
+        }
+    }
+
+    deserializer.deserialize_any(ImageVisitor)
+}
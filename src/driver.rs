@@ -0,0 +1,354 @@
+// SPDX-License-Identifier: MIT
+// Copyright 2024 brog Authors
+
+//! The bootc operations brog performs.
+//!
+//! [`BootcDriver`] is the extension point. [`CommandDriver`] runs the bootc
+//! binary on tokio with timeouts; [`FakeDriver`] keeps a [`BootcHost`] in
+//! memory so reconciliation can be tested without a bootc system.
+
+use crate::{
+    bootc::{BootEntry, BootcHost, ImageReference, ImageStatus},
+    command::{run_command_async, CommandError, CommandOutput},
+    error::BrogError,
+};
+use async_trait::async_trait;
+use std::{
+    collections::VecDeque, fmt, fs, os::unix::process::ExitStatusExt, path::PathBuf,
+    process::ExitStatus, sync::Mutex, time::Duration,
+};
+use tracing::warn;
+
+/// Timeout for commands that change the deployment. Pulling an image can
+/// take a while on a slow link.
+pub const DEFAULT_TIMEOUT: Duration = Duration::from_secs(30 * 60);
+pub const DEFAULT_STATUS_TIMEOUT: Duration = Duration::from_secs(120);
+
+/// How far `bootc upgrade` goes.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum UpgradeMode {
+    /// Only look for an update, `--check`.
+    Check,
+    /// Download and stage the update.
+    Stage,
+    /// Stage the update and reboot into it, `--apply`.
+    Apply,
+}
+
+#[async_trait]
+pub trait BootcDriver: fmt::Debug + Send + Sync {
+    async fn status(&self) -> Result<BootcHost, BrogError>;
+    /// `bootc switch`, rebooting into `image` when `apply` is set.
+    async fn switch(&self, image: &str, apply: bool) -> Result<CommandOutput, BrogError>;
+    async fn upgrade(&self, mode: UpgradeMode) -> Result<CommandOutput, BrogError>;
+    /// `bootc rollback`, rebooting into the rollback deployment when `apply`
+    /// is set.
+    async fn rollback(&self, apply: bool) -> Result<CommandOutput, BrogError>;
+    /// The kernel arguments of the booted deployment.
+    async fn kargs(&self) -> Result<Vec<String>, BrogError>;
+}
+
+/// A [`BootcDriver`] running the `bootc` found on `bin_path`.
+///
+/// A command running longer than its timeout is killed, as is one whose
+/// future is dropped, so a reconciliation can be cancelled at any point.
+#[derive(Debug, Clone)]
+pub struct CommandDriver {
+    bin_path: String,
+    timeout: Duration,
+    status_timeout: Duration,
+    cmdline: PathBuf,
+}
+
+impl CommandDriver {
+    pub fn new(bin_path: &str) -> Self {
+        CommandDriver {
+            bin_path: bin_path.to_owned(),
+            timeout: DEFAULT_TIMEOUT,
+            status_timeout: DEFAULT_STATUS_TIMEOUT,
+            cmdline: PathBuf::from("/proc/cmdline"),
+        }
+    }
+
+    /// The timeout for `switch`, `upgrade` and `rollback`.
+    pub fn with_timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = timeout;
+        self
+    }
+
+    /// The timeout for `status` and `upgrade --check`.
+    pub fn with_status_timeout(mut self, timeout: Duration) -> Self {
+        self.status_timeout = timeout;
+        self
+    }
+
+    /// bootc cannot print the kernel arguments, they are read from the
+    /// kernel command line at `path`, `/proc/cmdline` by default.
+    pub fn with_cmdline(mut self, path: &str) -> Self {
+        self.cmdline = PathBuf::from(path);
+        self
+    }
+
+    async fn run(&self, args: &[&str], timeout: Duration) -> Result<CommandOutput, CommandError> {
+        let output = run_command_async(args, &self.bin_path, timeout).await?;
+        if !output.stderr.trim().is_empty() {
+            warn!("bootc {:?}: {}", args, output.stderr.trim());
+        }
+        Ok(output)
+    }
+}
+
+#[async_trait]
+impl BootcDriver for CommandDriver {
+    async fn status(&self) -> Result<BootcHost, BrogError> {
+        let output = self
+            .run(&["status", "--format", "yaml"], self.status_timeout)
+            .await?;
+        BootcHost::from_yaml(&output.stdout)
+    }
+
+    async fn switch(&self, image: &str, apply: bool) -> Result<CommandOutput, BrogError> {
+        let mut args = vec!["switch", image];
+        if apply {
+            args.push("--apply");
+        }
+        Ok(self.run(&args, self.timeout).await?)
+    }
+
+    async fn upgrade(&self, mode: UpgradeMode) -> Result<CommandOutput, BrogError> {
+        let output = match mode {
+            UpgradeMode::Check => self.run(&["upgrade", "--check"], self.status_timeout),
+            UpgradeMode::Stage => self.run(&["upgrade"], self.timeout),
+            UpgradeMode::Apply => self.run(&["upgrade", "--apply"], self.timeout),
+        };
+        Ok(output.await?)
+    }
+
+    async fn rollback(&self, apply: bool) -> Result<CommandOutput, BrogError> {
+        let args: &[&str] = if apply {
+            &["rollback", "--apply"]
+        } else {
+            &["rollback"]
+        };
+        Ok(self.run(args, self.timeout).await?)
+    }
+
+    async fn kargs(&self) -> Result<Vec<String>, BrogError> {
+        let cmdline = fs::read_to_string(&self.cmdline).map_err(|e| {
+            BrogError::Other(anyhow::anyhow!(
+                "Cannot read kernel arguments {}: {}",
+                self.cmdline.display(),
+                e
+            ))
+        })?;
+        Ok(cmdline.split_whitespace().map(str::to_owned).collect())
+    }
+}
+
+/// An in-memory [`BootcDriver`].
+///
+/// Commands change the host the way bootc would: `switch` and `upgrade`
+/// stage a deployment and `--apply` boots into it at once, as if the
+/// machine had rebooted. Every command is recorded, see
+/// [`FakeDriver::calls`].
+#[derive(Debug, Default)]
+pub struct FakeDriver {
+    inner: Mutex<Fake>,
+}
+
+#[derive(Debug, Default)]
+struct Fake {
+    host: BootcHost,
+    kargs: Vec<String>,
+    digests: Vec<(String, String)>,
+    failures: VecDeque<String>,
+    calls: Vec<String>,
+}
+
+impl FakeDriver {
+    pub fn new(host: BootcHost) -> Self {
+        FakeDriver {
+            inner: Mutex::new(Fake {
+                host,
+                ..Default::default()
+            }),
+        }
+    }
+
+    /// A host booted into `image`.
+    pub fn booted(image: &str, digest: &str) -> Self {
+        let mut host = BootcHost::default();
+        host.status.booted = Some(BootEntry {
+            image: Some(image_status(image, digest)),
+            ..Default::default()
+        });
+        Self::new(host)
+    }
+
+    pub fn with_kargs(self, kargs: &[&str]) -> Self {
+        self.lock().kargs = kargs.iter().map(|k| k.to_string()).collect();
+        self
+    }
+
+    /// The digest `image` has when it is switched to.
+    pub fn with_digest(self, image: &str, digest: &str) -> Self {
+        self.lock()
+            .digests
+            .push((image.to_owned(), digest.to_owned()));
+        self
+    }
+
+    /// Makes the next command other than `status` fail with `stderr`.
+    pub fn fail_next(&self, stderr: &str) {
+        self.lock().failures.push_back(stderr.to_owned());
+    }
+
+    /// Publishes `update` as the new content of the booted image reference,
+    /// the way `bootc upgrade --check` would find it.
+    pub fn set_cached_update(&self, update: ImageStatus) {
+        if let Some(booted) = self.lock().host.status.booted.as_mut() {
+            booted.cached_update = Some(update);
+        }
+    }
+
+    pub fn host(&self) -> BootcHost {
+        self.lock().host.clone()
+    }
+
+    /// The commands run so far, e.g. `switch quay.io/fedora/fedora-bootc:41 --apply`.
+    pub fn calls(&self) -> Vec<String> {
+        self.lock().calls.clone()
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, Fake> {
+        self.inner.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    fn run(
+        &self,
+        args: &[&str],
+        change: impl FnOnce(&mut Fake),
+    ) -> Result<CommandOutput, BrogError> {
+        let mut fake = self.lock();
+        let call = args.join(" ");
+        fake.calls.push(call.clone());
+        if let Some(stderr) = fake.failures.pop_front() {
+            return Err(CommandError::Failed {
+                program: "bootc".to_owned(),
+                args: args.iter().map(|a| a.to_string()).collect(),
+                status: ExitStatus::from_raw(1 << 8),
+                stdout: String::new(),
+                stderr,
+                duration: Duration::ZERO,
+            }
+            .into());
+        }
+        change(&mut fake);
+        Ok(CommandOutput {
+            status: ExitStatus::from_raw(0),
+            stdout: format!("bootc {}\n", call),
+            stderr: String::new(),
+            duration: Duration::ZERO,
+        })
+    }
+}
+
+impl Fake {
+    fn stage(&mut self, status: ImageStatus) {
+        self.host.status.staged = Some(BootEntry {
+            image: Some(status),
+            ..Default::default()
+        });
+    }
+
+    /// Boots into the staged deployment, keeping the booted one for
+    /// rollback.
+    fn reboot(&mut self) {
+        if let Some(staged) = self.host.status.staged.take() {
+            self.host.status.rollback = self.host.status.booted.take();
+            self.host.status.booted = Some(staged);
+        }
+    }
+}
+
+#[async_trait]
+impl BootcDriver for FakeDriver {
+    async fn status(&self) -> Result<BootcHost, BrogError> {
+        Ok(self.host())
+    }
+
+    async fn switch(&self, image: &str, apply: bool) -> Result<CommandOutput, BrogError> {
+        let mut args = vec!["switch", image];
+        if apply {
+            args.push("--apply");
+        }
+        self.run(&args, |fake| {
+            let digest = fake
+                .digests
+                .iter()
+                .find(|(i, _)| i == image)
+                .map(|(_, d)| d.clone())
+                .unwrap_or_default();
+            fake.stage(image_status(image, &digest));
+            if apply {
+                fake.reboot();
+            }
+        })
+    }
+
+    async fn upgrade(&self, mode: UpgradeMode) -> Result<CommandOutput, BrogError> {
+        let args: &[&str] = match mode {
+            UpgradeMode::Check => &["upgrade", "--check"],
+            UpgradeMode::Stage => &["upgrade"],
+            UpgradeMode::Apply => &["upgrade", "--apply"],
+        };
+        self.run(args, |fake| {
+            if mode == UpgradeMode::Check {
+                return;
+            }
+            let update = fake
+                .host
+                .status
+                .booted
+                .as_mut()
+                .and_then(|b| b.cached_update.take());
+            if let Some(update) = update {
+                fake.stage(update);
+            }
+            if mode == UpgradeMode::Apply {
+                fake.reboot();
+            }
+        })
+    }
+
+    async fn rollback(&self, apply: bool) -> Result<CommandOutput, BrogError> {
+        let args: &[&str] = if apply {
+            &["rollback", "--apply"]
+        } else {
+            &["rollback"]
+        };
+        self.run(args, |fake| {
+            let status = &mut fake.host.status;
+            if apply {
+                std::mem::swap(&mut status.booted, &mut status.rollback);
+            } else {
+                status.rollback_queued = true;
+            }
+        })
+    }
+
+    async fn kargs(&self) -> Result<Vec<String>, BrogError> {
+        Ok(self.lock().kargs.clone())
+    }
+}
+
+fn image_status(image: &str, digest: &str) -> ImageStatus {
+    ImageStatus {
+        image: ImageReference {
+            image: image.to_owned(),
+            transport: "registry".to_owned(),
+        },
+        image_digest: digest.to_owned(),
+        ..Default::default()
+    }
+}
@@ -0,0 +1,172 @@
+// SPDX-License-Identifier: MIT
+// Copyright 2024 brog Authors
+
+//! The errors brog reports to its callers.
+//!
+//! Internally brog passes `anyhow::Error`s around, with a [`BrogError`]
+//! inside wherever the kind of failure is known. The public entry points
+//! turn them back into a [`BrogError`], so a supervisor can tell an
+//! unreachable endpoint from a bad config or a failed bootc command.
+
+use crate::{command::CommandError, retry};
+use reqwest::StatusCode;
+use std::fmt;
+
+#[derive(Debug)]
+#[non_exhaustive]
+pub enum BrogError {
+    /// The agent settings or the fetched brog.yaml cannot be acted on.
+    Config { message: String },
+    /// `url` could not be reached or the response could not be read.
+    Transport { url: String, source: reqwest::Error },
+    /// `url` answered with an unexpected status.
+    HttpStatus { url: String, status: u16 },
+    /// A request could not be signed or a config signature did not verify.
+    Auth { message: String },
+    /// `what`, e.g. `brog.yaml` or `bootc status`, is not valid.
+    Parse { what: String, message: String },
+    /// A local rule refused `image`. `policy` names the rule, e.g.
+    /// `imagePolicy` or `allowDowngrade`.
+    PolicyRejected {
+        image: String,
+        policy: String,
+        reason: String,
+    },
+    /// [`crate::Agent::apply_staged`] found no deployment to apply: nothing
+    /// is staged, or the staged image is not the `target`, e.g. because its
+    /// tag has moved since.
+    NotStaged {
+        staged: Option<String>,
+        target: Option<String>,
+    },
+    /// bootc failed, timed out or could not be started.
+    Command(CommandError),
+    /// Anything else, such as a state file that cannot be written.
+    Other(anyhow::Error),
+}
+
+impl BrogError {
+    pub fn config(message: impl Into<String>) -> Self {
+        BrogError::Config {
+            message: message.into(),
+        }
+    }
+
+    pub fn auth(message: impl Into<String>) -> Self {
+        BrogError::Auth {
+            message: message.into(),
+        }
+    }
+
+    pub fn parse(what: &str, message: impl fmt::Display) -> Self {
+        BrogError::Parse {
+            what: what.to_owned(),
+            message: message.to_string(),
+        }
+    }
+
+    pub fn http_status(url: &str, status: StatusCode) -> Self {
+        BrogError::HttpStatus {
+            url: url.to_owned(),
+            status: status.as_u16(),
+        }
+    }
+
+    /// The HTTP status code for [`BrogError::HttpStatus`].
+    pub fn status(&self) -> Option<u16> {
+        match self {
+            BrogError::HttpStatus { status, .. } => Some(*status),
+            _ => None,
+        }
+    }
+
+    /// True for failures that may go away on their own: network errors,
+    /// 408, 429 and 5xx responses and bootc timeouts.
+    pub fn is_retryable(&self) -> bool {
+        match self {
+            BrogError::Transport { .. } => true,
+            BrogError::HttpStatus { status, .. } => {
+                StatusCode::from_u16(*status).is_ok_and(retry::is_transient_status)
+            }
+            BrogError::Command(CommandError::TimedOut { .. }) => true,
+            _ => false,
+        }
+    }
+}
+
+impl fmt::Display for BrogError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            BrogError::Config { message } | BrogError::Auth { message } => {
+                write!(f, "{}", message)
+            }
+            BrogError::Transport { url, source } => {
+                write!(f, "Request to {} failed: {}", url, source)
+            }
+            BrogError::HttpStatus { url, status } => {
+                let status = StatusCode::from_u16(*status)
+                    .map_or_else(|_| status.to_string(), |s| s.to_string());
+                write!(f, "Invalid request: {}, {}", status, url)
+            }
+            BrogError::Parse { what, message } => write!(f, "Invalid {}: {}", what, message),
+            BrogError::PolicyRejected { reason, .. } => write!(f, "{}", reason),
+            BrogError::NotStaged { staged: None, .. } => write!(f, "Nothing is staged"),
+            BrogError::NotStaged {
+                staged: Some(staged),
+                target,
+            } => write!(
+                f,
+                "Staged image {} is not the target {}",
+                staged,
+                target.as_deref().unwrap_or("-")
+            ),
+            BrogError::Command(e) => write!(f, "{}", e),
+            BrogError::Other(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+impl std::error::Error for BrogError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            BrogError::Transport { source, .. } => Some(source),
+            BrogError::Command(e) => std::error::Error::source(e),
+            BrogError::Other(e) => e.source(),
+            _ => None,
+        }
+    }
+}
+
+impl From<CommandError> for BrogError {
+    fn from(e: CommandError) -> Self {
+        BrogError::Command(e)
+    }
+}
+
+impl From<reqwest::Error> for BrogError {
+    fn from(e: reqwest::Error) -> Self {
+        BrogError::Transport {
+            url: e.url().map(|u| u.to_string()).unwrap_or_default(),
+            source: e,
+        }
+    }
+}
+
+/// Recovers the [`BrogError`], [`CommandError`] or `reqwest::Error` inside
+/// `e`, falling back to [`BrogError::Other`].
+impl From<anyhow::Error> for BrogError {
+    fn from(e: anyhow::Error) -> Self {
+        let e = match e.downcast::<BrogError>() {
+            Ok(e) => return e,
+            Err(e) => e,
+        };
+        let e = match e.downcast::<CommandError>() {
+            Ok(e) => return e.into(),
+            Err(e) => e,
+        };
+        match e.downcast::<reqwest::Error>() {
+            Ok(e) => e.into(),
+            Err(e) => BrogError::Other(e),
+        }
+    }
+}
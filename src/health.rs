@@ -0,0 +1,343 @@
+// SPDX-License-Identifier: MIT
+// Copyright 2024 brog Authors
+
+//! Post-update health checks with automatic rollback.
+//!
+//! Before brog switches to an image that has a `healthCheck` configured it
+//! records the image as pending. On every start brog compares the booted
+//! image with the pending one and, when they match, runs the checks. A
+//! deployment that is still failing after `maxBoots` boots is rolled back
+//! with `bootc rollback --apply` and the image is never retried.
+//!
+//! The pending check and the rollback history live in the [`State`].
+
+use crate::{
+    bootc::ImageStatus,
+    driver::{BootcDriver, CommandDriver},
+    error::BrogError,
+    state::{RollbackRecord, State, StateStore},
+};
+use serde::{Deserialize, Serialize};
+use std::{
+    fs,
+    process::{ExitStatus, Stdio},
+    time::{Duration, Instant},
+};
+use tracing::{debug, info, warn};
+
+const BOOT_ID_PATH: &str = "/proc/sys/kernel/random/boot_id";
+const CHECK_INTERVAL: Duration = Duration::from_secs(5);
+const PROBE_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// The `healthCheck` section of a client config.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default, rename_all = "camelCase")]
+pub struct HealthCheck {
+    /// systemd units that must be active.
+    pub units: Vec<String>,
+    /// A URL on localhost that must answer with a 2xx status.
+    pub http: Option<String>,
+    /// A script that must exit with status 0.
+    pub script: Option<String>,
+    /// Seconds to keep retrying the checks after boot.
+    pub deadline: u64,
+    /// Number of boots the deployment may fail on before it is rolled back.
+    pub max_boots: u32,
+}
+
+impl Default for HealthCheck {
+    fn default() -> Self {
+        HealthCheck {
+            units: vec![],
+            http: None,
+            script: None,
+            deadline: 300,
+            max_boots: 1,
+        }
+    }
+}
+
+impl HealthCheck {
+    pub(crate) fn validate(&self, path: &str) -> Result<(), anyhow::Error> {
+        if let Some(http) = &self.http {
+            let url = url::Url::parse(http).map_err(|e| {
+                anyhow::anyhow!("Invalid brog.yaml: {}.healthCheck.http: {}", path, e)
+            })?;
+            if !matches!(
+                url.host_str(),
+                Some("localhost") | Some("127.0.0.1") | Some("[::1]")
+            ) {
+                return Err(anyhow::anyhow!(
+                    "Invalid brog.yaml: {}.healthCheck.http: {} is not on localhost",
+                    path,
+                    http
+                ));
+            }
+        }
+        if self.max_boots == 0 {
+            return Err(anyhow::anyhow!(
+                "Invalid brog.yaml: {}.healthCheck.maxBoots: must be at least 1",
+                path
+            ));
+        }
+        Ok(())
+    }
+
+    /// Runs every check once, returning the first failure. A command still
+    /// running when the returned future is dropped is killed.
+    pub async fn run_once(
+        &self,
+        bin_path: &str,
+        client: &reqwest::Client,
+    ) -> Result<(), anyhow::Error> {
+        for unit in &self.units {
+            let status = run_quiet("systemctl", &["is-active", "--quiet", unit], bin_path).await?;
+            if !status.success() {
+                return Err(anyhow::anyhow!("unit {} is not active", unit));
+            }
+        }
+        if let Some(http) = &self.http {
+            let res = client.get(http).timeout(PROBE_TIMEOUT).send().await?;
+            if !res.status().is_success() {
+                return Err(anyhow::anyhow!("{} returned {}", http, res.status()));
+            }
+        }
+        if let Some(script) = &self.script {
+            let status = run_quiet(script, &[], bin_path).await?;
+            if !status.success() {
+                return Err(anyhow::anyhow!("{} exited with {}", script, status));
+            }
+        }
+        Ok(())
+    }
+
+    /// Runs the checks until they pass or the deadline expires. An attempt
+    /// still running at the deadline is killed, though every attempt gets at
+    /// least five seconds.
+    pub async fn run(&self, bin_path: &str, client: &reqwest::Client) -> Result<(), anyhow::Error> {
+        let deadline = Instant::now() + Duration::from_secs(self.deadline);
+        loop {
+            let limit = deadline
+                .saturating_duration_since(Instant::now())
+                .max(PROBE_TIMEOUT);
+            let result = tokio::time::timeout(limit, self.run_once(bin_path, client))
+                .await
+                .unwrap_or_else(|_| {
+                    Err(anyhow::anyhow!(
+                        "health checks did not finish within {:?}",
+                        limit
+                    ))
+                });
+            match result {
+                Ok(()) => return Ok(()),
+                Err(e) if Instant::now() + CHECK_INTERVAL < deadline => {
+                    debug!("Health check failed, retrying: {}", e);
+                    tokio::time::sleep(CHECK_INTERVAL).await;
+                }
+                Err(e) => return Err(e),
+            }
+        }
+    }
+}
+
+async fn run_quiet(
+    program: &str,
+    args: &[&str],
+    bin_path: &str,
+) -> Result<ExitStatus, anyhow::Error> {
+    tokio::process::Command::new(program)
+        .env("PATH", bin_path)
+        .args(args)
+        .stdout(Stdio::null())
+        .stderr(Stdio::null())
+        .kill_on_drop(true)
+        .status()
+        .await
+        .map_err(|e| anyhow::anyhow!("failed to execute {}: {}", program, e))
+}
+
+/// An update whose health has not yet been confirmed.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct PendingCheck {
+    pub image: String,
+    /// The digest being checked. Without one any deployment of `image` is
+    /// checked.
+    #[serde(default)]
+    pub digest: Option<String>,
+    pub check: HealthCheck,
+    #[serde(default)]
+    pub boots: u32,
+    #[serde(default)]
+    pub boot_id: Option<String>,
+}
+
+impl PendingCheck {
+    /// True when `status` is the deployment this check is for. After an
+    /// in-place upgrade the previous deployment has the same tag, so the
+    /// digest decides.
+    pub fn is_for(&self, status: &ImageStatus) -> bool {
+        status.matches(&self.image)
+            && match &self.digest {
+                Some(digest) => *digest == status.image_digest,
+                None => true,
+            }
+    }
+}
+
+/// What [`verify_pending`] found.
+#[derive(Debug, Clone, PartialEq)]
+pub enum HealthOutcome {
+    /// No update is awaiting a health check.
+    NothingPending,
+    /// The pending image is not booted yet.
+    NotBooted,
+    /// The checks already ran during this boot.
+    AlreadyChecked,
+    Healthy,
+    /// The checks failed but the deployment has boots left.
+    Unhealthy {
+        boots: u32,
+        reason: String,
+    },
+    RolledBack {
+        image: String,
+        /// The deployment booted instead, as `image@digest`.
+        rolled_back_to: Option<String>,
+        reason: String,
+    },
+}
+
+/// Runs the health checks for a pending update if it is the booted image,
+/// rolling back once it has failed on `maxBoots` boots.
+pub async fn verify_pending(
+    service_location: &str,
+    bin_path: &str,
+) -> Result<HealthOutcome, BrogError> {
+    let driver = CommandDriver::new(bin_path);
+    Ok(verify(service_location, bin_path, &driver, &reqwest::Client::new()).await?)
+}
+
+pub(crate) async fn verify(
+    service_location: &str,
+    bin_path: &str,
+    driver: &dyn BootcDriver,
+    client: &reqwest::Client,
+) -> Result<HealthOutcome, anyhow::Error> {
+    let store = StateStore::new(service_location);
+    let mut state: State = store.load()?;
+    let mut pending = match state.pending_check.clone() {
+        Some(p) => p,
+        None => return Ok(HealthOutcome::NothingPending),
+    };
+
+    let host = driver.status().await?;
+    let booted = match host.booted_image().filter(|b| pending.is_for(b)) {
+        Some(booted) => booted,
+        // The reboot of `bootc rollback --apply` stopped brog before it
+        // recorded the rollback.
+        None if pending.boots >= pending.check.max_boots
+            && host.rollback_image().is_some_and(|r| pending.is_for(r)) =>
+        {
+            let reason = format!("failed health checks on {} boots", pending.boots);
+            let digest = host.rollback_image().map(|r| r.image_digest.clone());
+            record_rollback(&mut state, &pending, digest, &reason);
+            store.save(&state)?;
+            return Ok(HealthOutcome::RolledBack {
+                image: pending.image,
+                rolled_back_to: host.booted_image().map(deployment),
+                reason,
+            });
+        }
+        None => {
+            debug!("Pending image {} is not booted", pending.image);
+            return Ok(HealthOutcome::NotBooted);
+        }
+    };
+
+    let boot_id = fs::read_to_string(BOOT_ID_PATH)
+        .ok()
+        .map(|id| id.trim().to_owned());
+    if boot_id.is_some() && boot_id == pending.boot_id {
+        return Ok(HealthOutcome::AlreadyChecked);
+    }
+    pending.boots += 1;
+    pending.boot_id = boot_id;
+    state.pending_check = Some(pending.clone());
+    store.save(&state)?;
+
+    info!(
+        "Running health checks for {} (boot {}/{})",
+        pending.image, pending.boots, pending.check.max_boots
+    );
+    let reason = match pending.check.run(bin_path, client).await {
+        Ok(()) => {
+            info!("{} is healthy", pending.image);
+            state.pending_check = None;
+            state.record("healthy", &pending.image, None);
+            store.save(&state)?;
+            return Ok(HealthOutcome::Healthy);
+        }
+        Err(e) => e.to_string(),
+    };
+
+    if pending.boots < pending.check.max_boots {
+        warn!(
+            "{} failed health checks on boot {}/{}: {}",
+            pending.image, pending.boots, pending.check.max_boots, reason
+        );
+        return Ok(HealthOutcome::Unhealthy {
+            boots: pending.boots,
+            reason,
+        });
+    }
+
+    warn!(
+        "{} failed health checks, rolling back: {}",
+        pending.image, reason
+    );
+    let digest = Some(booted.image_digest.clone());
+    let rolled_back_to = host.rollback_image().map(deployment);
+    let output = match driver.rollback(true).await {
+        Ok(output) => output,
+        Err(e) => {
+            // Run the checks and the rollback again on the next start.
+            pending.boot_id = None;
+            state.pending_check = Some(pending.clone());
+            state.record("rollbackFailed", &pending.image, Some(e.to_string()));
+            store.save(&state)?;
+            return Err(e.into());
+        }
+    };
+    debug!("bootc output:{}", output.stdout);
+    record_rollback(&mut state, &pending, digest, &reason);
+    store.save(&state)?;
+    Ok(HealthOutcome::RolledBack {
+        image: pending.image,
+        rolled_back_to,
+        reason,
+    })
+}
+
+fn deployment(status: &ImageStatus) -> String {
+    format!("{}@{}", status.image.image, status.image_digest)
+}
+
+/// Clears the pending check and refuses its image from now on.
+fn record_rollback(
+    state: &mut State,
+    pending: &PendingCheck,
+    digest: Option<String>,
+    reason: &str,
+) {
+    state.pending_check = None;
+    state.rollbacks.push(RollbackRecord {
+        image: pending.image.clone(),
+        digest: digest
+            .filter(|d| !d.is_empty())
+            .or_else(|| pending.digest.clone()),
+        reason: reason.to_owned(),
+        at: chrono::Utc::now(),
+    });
+    state.record("rolledBack", &pending.image, Some(reason.to_owned()));
+}
@@ -0,0 +1,90 @@
+// SPDX-License-Identifier: MIT
+// Copyright 2024 brog Authors
+
+//! Container image references as written in brog.yaml.
+//!
+//! References follow the usual `[registry/]repository[:tag][@digest]` form.
+//! The first path component is a registry when it contains a `.` or `:` or
+//! is `localhost`; otherwise the image lives on `docker.io`.
+
+use std::fmt;
+
+pub const DEFAULT_REGISTRY: &str = "docker.io";
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ImageRef {
+    pub registry: String,
+    /// The path within the registry, e.g. `fedora/fedora-bootc`.
+    pub repository: String,
+    pub tag: Option<String>,
+    /// `algorithm:hex`, e.g. `sha256:0b5a...`.
+    pub digest: Option<String>,
+}
+
+impl ImageRef {
+    pub fn parse(reference: &str) -> Result<Self, anyhow::Error> {
+        let invalid =
+            |reason: &str| anyhow::anyhow!("Invalid image reference {:?}: {}", reference, reason);
+        if reference.is_empty() || reference.contains(char::is_whitespace) {
+            return Err(invalid("must be a non-empty string without whitespace"));
+        }
+        let (name, digest) = match reference.split_once('@') {
+            Some((name, digest)) => {
+                let (algorithm, hex) = digest
+                    .split_once(':')
+                    .ok_or_else(|| invalid("digest must be algorithm:hex"))?;
+                if algorithm.is_empty()
+                    || hex.is_empty()
+                    || !hex.chars().all(|c| c.is_ascii_hexdigit())
+                {
+                    return Err(invalid("digest must be algorithm:hex"));
+                }
+                (name, Some(digest.to_owned()))
+            }
+            None => (reference, None),
+        };
+        let (name, tag) = match name.rfind(':') {
+            Some(i) if !name[i..].contains('/') => (&name[..i], Some(name[i + 1..].to_owned())),
+            _ => (name, None),
+        };
+        if tag.as_deref() == Some("") {
+            return Err(invalid("tag is empty"));
+        }
+        let (registry, repository) = match name.split_once('/') {
+            Some((first, rest))
+                if first.contains('.') || first.contains(':') || first == "localhost" =>
+            {
+                (first.to_owned(), rest.to_owned())
+            }
+            Some(_) => (DEFAULT_REGISTRY.to_owned(), name.to_owned()),
+            None => (DEFAULT_REGISTRY.to_owned(), format!("library/{}", name)),
+        };
+        if repository.is_empty() || repository.split('/').any(str::is_empty) {
+            return Err(invalid("repository is empty"));
+        }
+        Ok(ImageRef {
+            registry,
+            repository,
+            tag,
+            digest,
+        })
+    }
+
+    /// `registry/repository` without tag or digest.
+    pub fn name(&self) -> String {
+        format!("{}/{}", self.registry, self.repository)
+    }
+}
+
+impl fmt::Display for ImageRef {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.name())?;
+        if let Some(tag) = &self.tag {
+            write!(f, ":{}", tag)?;
+        }
+        if let Some(digest) = &self.digest {
+            write!(f, "@{}", digest)?;
+        }
+        Ok(())
+    }
+}
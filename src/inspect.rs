@@ -0,0 +1,145 @@
+// SPDX-License-Identifier: MIT
+// Copyright 2024 brog Authors
+
+//! Read-only views of a brog host.
+//!
+//! These are what `brog status`, `brog history` and `brog check-config`
+//! print. They read the state file and bootc but never change anything, so
+//! a supervisor can show the same views.
+
+use crate::{
+    config::BrogDocument, driver::BootcDriver, error::BrogError, policy::ImagePolicy,
+    settings::BrogConfig, state::StateStore,
+};
+use std::{fmt::Write as _, fs, path::Path};
+
+/// The booted, staged and rollback images from bootc together with the
+/// target image and the agent state. A failing `bootc status` is shown
+/// rather than returned.
+pub async fn status(
+    config: &BrogConfig,
+    driver: &dyn BootcDriver,
+    json: bool,
+) -> Result<String, BrogError> {
+    let state = StateStore::new(&config.config_path).load()?;
+    let host = driver.status().await;
+    if json {
+        let host = match &host {
+            Ok(h) => serde_json::to_value(h).map_err(|e| BrogError::Other(e.into()))?,
+            Err(e) => serde_json::json!({ "error": e.to_string() }),
+        };
+        return serde_json::to_string_pretty(&serde_json::json!({ "host": host, "state": state }))
+            .map(|json| json + "\n")
+            .map_err(|e| BrogError::Other(e.into()));
+    }
+
+    fn show<T: std::fmt::Display>(value: &Option<T>) -> String {
+        value
+            .as_ref()
+            .map_or_else(|| "-".to_owned(), |v| v.to_string())
+    }
+    let mut out = String::new();
+    match &host {
+        Ok(host) => {
+            for (name, image) in [
+                ("booted", host.booted_image()),
+                ("staged", host.staged_image()),
+                ("rollback", host.rollback_image()),
+            ] {
+                match image {
+                    Some(i) => {
+                        let _ = writeln!(out, "{:<14}{}@{}", name, i.image.image, i.image_digest);
+                    }
+                    None => {
+                        let _ = writeln!(out, "{:<14}-", name);
+                    }
+                }
+            }
+        }
+        Err(e) => {
+            let _ = writeln!(out, "{:<14}unavailable: {}", "booted", e);
+        }
+    }
+    let canary = state.canary_percent.map(|p| format!("{}%", p));
+    let pending = state.pending_check.as_ref().map(|p| {
+        format!(
+            "{} pending (boot {}/{})",
+            p.image, p.boots, p.check.max_boots
+        )
+    });
+    for (name, value) in [
+        ("target", show(&state.target_image)),
+        ("applied", show(&state.applied_image)),
+        ("commit", show(&state.commit)),
+        ("last fetch", show(&state.last_fetch)),
+        ("last success", show(&state.last_success)),
+        ("last failure", show(&state.last_failure)),
+        ("last error", show(&state.last_error)),
+        ("attempts", state.attempts.to_string()),
+        ("canary", show(&canary)),
+        ("health check", show(&pending)),
+    ] {
+        let _ = writeln!(out, "{:<14}{}", name, value);
+    }
+    Ok(out)
+}
+
+/// Recent switches, failures, health checks and rollbacks, oldest first.
+pub fn history(config: &BrogConfig, json: bool) -> Result<String, BrogError> {
+    let state = StateStore::new(&config.config_path).load()?;
+    if json {
+        return serde_json::to_string_pretty(&state.history)
+            .map(|json| json + "\n")
+            .map_err(|e| BrogError::Other(e.into()));
+    }
+    let mut out = String::new();
+    for entry in &state.history {
+        let _ = writeln!(
+            out,
+            "{}  {:<13}{}{}",
+            entry.at.format("%Y-%m-%dT%H:%M:%SZ"),
+            entry.event,
+            entry.image,
+            entry
+                .detail
+                .as_ref()
+                .map_or_else(String::new, |d| format!(" ({})", d))
+        );
+    }
+    Ok(out)
+}
+
+/// Validates the brog.yaml at `location`, a path or an http(s) URL, and
+/// checks its image against the image policy at `policy_path`. Unknown
+/// fields are reported as warnings in the returned text.
+pub async fn check_config(location: &str, policy_path: Option<&str>) -> Result<String, BrogError> {
+    let text = if location.starts_with("http://") || location.starts_with("https://") {
+        let res = reqwest::get(location).await?;
+        if !res.status().is_success() {
+            return Err(BrogError::http_status(location, res.status()));
+        }
+        res.text().await?
+    } else {
+        fs::read_to_string(location)
+            .map_err(|e| BrogError::config(format!("Cannot read {}: {}", location, e)))?
+    };
+    let document = BrogDocument::from_yaml(&text)?;
+    let mut out = String::new();
+    for field in document.unknown_fields() {
+        let _ = writeln!(out, "warning: unknown field {}", field);
+    }
+    let policy = match policy_path {
+        Some(path) => ImagePolicy::load(Path::new(path))?,
+        None => None,
+    };
+    if let (Some(policy), Some(client)) = (policy, document.client()) {
+        policy.check(&client.image)?;
+    }
+    let _ = writeln!(
+        out,
+        "{} is valid, image: {}",
+        location,
+        document.client().map_or("-", |c| c.image.as_str())
+    );
+    Ok(out)
+}
@@ -1,16 +1,133 @@
-use messagesign::signature;
-use rand::Rng;
-use reqwest::header::{HeaderMap, HeaderName, HeaderValue, AUTHORIZATION};
-use std::io::Read;
-use std::{
-    fs,
-    io::Write,
-    path::Path,
-    process::{Command, Stdio},
+pub mod agent;
+pub mod bootc;
+pub mod canary;
+pub mod command;
+pub mod config;
+pub mod driver;
+pub mod error;
+pub mod health;
+pub mod image;
+pub mod inspect;
+pub mod metrics;
+pub mod outcome;
+pub mod policy;
+pub mod registry;
+pub mod report;
+pub mod retry;
+pub mod settings;
+pub mod signer;
+pub mod splay;
+pub mod state;
+pub mod verify;
+pub mod version;
+pub mod window;
+
+pub use agent::Agent;
+pub use command::{run_command, CommandError, CommandOutput};
+pub use config::{BrogDocument, ClientConfig, ClientConfigs};
+pub use driver::{BootcDriver, CommandDriver, FakeDriver};
+pub use error::BrogError;
+pub use metrics::Metrics;
+pub use outcome::{Outcome, ReconcileOutcome};
+pub use settings::BrogConfig;
+pub use state::{State, StateStore};
+
+use reqwest::header::{
+    HeaderMap, HeaderName, HeaderValue, ETAG, IF_MODIFIED_SINCE, IF_NONE_MATCH, LAST_MODIFIED,
 };
-use tracing::{debug, info};
+use serde::Serialize;
+use std::{fmt, fs, sync::Arc, time::Duration};
+use tracing::{debug, info, warn};
+
+const RETRY_DELAY: Duration = Duration::from_secs(2);
+
+/// What a reconciliation does beyond the connection details, see
+/// [`BrogConfig::options`].
+#[derive(Debug, Clone)]
+pub(crate) struct ProcessOptions {
+    /// Evaluate everything up to `bootc switch` and report the [`Plan`]
+    /// without switching or writing state.
+    pub dry_run: bool,
+    pub fetch_retry: retry::RetryPolicy,
+    /// Timeout for a single config fetch.
+    pub fetch_timeout: Duration,
+    /// Only stage new images. Rebooting into them waits for
+    /// [`Agent::apply_staged`].
+    pub stage_only: bool,
+    /// The messagesign region, see [`signer::DEFAULT_REGION`].
+    pub region: String,
+    /// Where to POST a [`report::Report`] after each reconciliation.
+    pub report_url: Option<String>,
+    /// A directory of trusted public keys. When set, configs must carry a
+    /// valid signature, see [`verify`].
+    pub signature_keys: Option<String>,
+    /// The [`policy::ImagePolicy`] file. A missing file allows any image.
+    pub policy_path: Option<String>,
+    /// Resolves tags for `resolveDigest`. Defaults to
+    /// [`registry::HttpRegistryClient`].
+    pub registry: Option<Arc<dyn registry::RegistryClient>>,
+    /// Runs bootc. Defaults to a [`CommandDriver`] on the `bin_path`.
+    pub driver: Option<Arc<dyn BootcDriver>>,
+    /// Timeout for bootc commands that change the deployment, see
+    /// [`CommandDriver::with_timeout`].
+    pub bootc_timeout: Duration,
+    /// Maintenance windows set on the device. They replace the
+    /// `maintenanceWindows` of the fetched config when set.
+    pub maintenance_windows: Option<Vec<window::MaintenanceWindow>>,
+    /// Where the device identity is read from.
+    pub machine_id_path: String,
+    pub hostname_path: String,
+    /// Records fetches, bootc commands and outcomes, see [`metrics`].
+    pub metrics: Option<Arc<Metrics>>,
+}
+
+impl ProcessOptions {
+    pub(crate) fn driver(&self, bin_path: &str) -> Arc<dyn BootcDriver> {
+        let driver: Arc<dyn BootcDriver> = match &self.driver {
+            Some(driver) => driver.clone(),
+            None => Arc::new(CommandDriver::new(bin_path).with_timeout(self.bootc_timeout)),
+        };
+        self.metered(driver)
+    }
+
+    /// `driver`, timed into [`ProcessOptions::metrics`] when set.
+    pub(crate) fn metered(&self, driver: Arc<dyn BootcDriver>) -> Arc<dyn BootcDriver> {
+        match &self.metrics {
+            Some(metrics) => Arc::new(metrics::MeteredDriver::new(driver, metrics.clone())),
+            None => driver,
+        }
+    }
+}
 
-#[tracing::instrument(name = "execute process", skip(secret))]
+/// What a reconciliation decided to do.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Plan {
+    /// The booted image as `image@digest`.
+    pub from: Option<String>,
+    pub to: String,
+    pub switch: bool,
+    /// Whether the switch reboots into the image or only stages it.
+    pub apply: bool,
+    pub reason: String,
+    pub dry_run: bool,
+}
+
+impl fmt::Display for Plan {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let from = self.from.as_deref().unwrap_or("nothing");
+        match (self.switch, self.apply, self.dry_run) {
+            (true, true, true) => write!(f, "would switch from {} to {}", from, self.to),
+            (true, true, false) => write!(f, "switched from {} to {}", from, self.to),
+            (true, false, true) => write!(f, "would stage {}: {}", self.to, self.reason),
+            (true, false, false) => write!(f, "staged {}: {}", self.to, self.reason),
+            (false, _, _) => write!(f, "no switch to {}: {}", self.to, self.reason),
+        }
+    }
+}
+
+/// Reconciles once with the default settings.
+#[deprecated(note = "build a BrogConfig and use Agent::reconcile")]
 pub async fn process(
     ep: String,
     key: String,
@@ -18,148 +135,646 @@ pub async fn process(
     bin_path: String,
     servicename: String,
     service_location: String,
-) -> Result<String, anyhow::Error> {
-    if ep == *"" {
-        return Err(anyhow::anyhow!("ENTRYPOINT cannot be empty"));
-    }
+) -> Result<ReconcileOutcome, BrogError> {
+    let config = BrogConfig {
+        endpoint: ep,
+        service_key: key,
+        service_secret: secret,
+        service_name: servicename,
+        bin_path,
+        config_path: service_location,
+        ..BrogConfig::default()
+    };
+    Agent::new(config)?.reconcile().await
+}
 
-    let machineid = fs::read_to_string("/etc/machine-id")?;
+pub(crate) async fn reconcile(
+    ep: &str,
+    driver: &dyn BootcDriver,
+    client: &reqwest::Client,
+    signer: &signer::Signer,
+    store: &StateStore,
+    state: &mut State,
+    options: &ProcessOptions,
+) -> Result<(Outcome, Plan), anyhow::Error> {
+    let machineid = fs::read_to_string(&options.machine_id_path)?;
     debug!("machineid: {}", machineid);
 
-    let hostname = fs::read_to_string("/proc/sys/kernel/hostname")?;
+    let hostname = fs::read_to_string(&options.hostname_path)?;
     debug!("hostname: {}", hostname);
+    let signer = signer.clone().with_identity(&machineid, &hostname);
 
-    let client = reqwest::Client::new();
+    let cache = state.config_cache.clone().filter(|c| c.url == ep);
+    let policy = &options.fetch_retry;
+    let mut attempt = 1;
+    let res = loop {
+        let mut headers = HeaderMap::new();
+        if signer.is_enabled() {
+            headers = signer.sign("GET", ep, None)?;
+            if let Some(commit) = state.commit.as_deref().filter(|c| !c.is_empty()) {
+                let shavalue = HeaderValue::from_str(commit)?;
+                debug!("Setting x-clos-commit: {}", commit);
+                headers.insert(HeaderName::from_static("x-clos-commit"), shavalue);
+            }
+        }
+        if let Some(cache) = &cache {
+            if let Some(etag) = &cache.etag {
+                headers.insert(IF_NONE_MATCH, HeaderValue::from_str(etag)?);
+            }
+            if let Some(last_modified) = &cache.last_modified {
+                headers.insert(IF_MODIFIED_SINCE, HeaderValue::from_str(last_modified)?);
+            }
+        }
 
-    let mut headers = HeaderMap::new();
-    let mut shapath: String = service_location.clone();
-    shapath.push_str("/sha");
+        debug!("Sending Headers:{:#?}", headers);
 
-    if !secret.is_empty() {
-        let method = "GET";
-        let payload_hash = "UNSIGNED-PAYLOAD";
-        let region = "global";
-        let service = servicename;
+        let result = client.get(ep).headers(headers).send().await;
+        if let Some(metrics) = &options.metrics {
+            metrics.fetched(&result, cache.is_some());
+        }
+        let retry_after = match result {
+            Ok(res)
+                if retry::is_transient_status(res.status()) && attempt < policy.max_attempts =>
+            {
+                warn!(
+                    "Fetch attempt {}/{} failed: {}",
+                    attempt,
+                    policy.max_attempts,
+                    res.status()
+                );
+                retry::retry_after(res.headers())
+            }
+            Err(e) if retry::is_transient_error(&e) && attempt < policy.max_attempts => {
+                warn!(
+                    "Fetch attempt {}/{} failed: {}",
+                    attempt, policy.max_attempts, e
+                );
+                None
+            }
+            result => break result,
+        };
+        let delay = policy.delay(attempt, retry_after);
+        debug!("Retrying fetch in {:?}", delay);
+        tokio::time::sleep(delay).await;
+        attempt += 1;
+    };
+    state.fetch_attempts = attempt;
+    let res = res.map_err(|source| BrogError::Transport {
+        url: ep.to_owned(),
+        source,
+    })?;
+    let not_modified = res.status() == reqwest::StatusCode::NOT_MODIFIED && cache.is_some();
+    if res.status() != reqwest::StatusCode::OK && !not_modified {
+        return Err(BrogError::http_status(ep, res.status()).into());
+    }
+    state.last_fetch = Some(chrono::Utc::now());
+    let sha = res.headers().get("x-clos-commit");
+    if let Some(commit) = sha.and_then(|c| c.to_str().ok()).filter(|c| !c.is_empty()) {
+        debug!("Recording commit: {}", commit);
+        state.commit = Some(commit.to_owned());
+    }
 
-        let mut rng = rand::rng();
-        let random_number = rng.random::<u32>();
+    let mut validators = None;
+    let (resptext, signature) = match cache.filter(|_| not_modified) {
+        Some(cache) => {
+            debug!("{} not modified, using cached config", ep);
+            (cache.body, cache.signature)
+        }
+        None => {
+            let header = |name| {
+                res.headers()
+                    .get(name)
+                    .and_then(|v: &HeaderValue| v.to_str().ok())
+                    .map(str::to_owned)
+            };
+            validators = Some((header(ETAG), header(LAST_MODIFIED)));
+            let signature = header(verify::SIGNATURE_HEADER);
+            (res.text().await?, signature)
+        }
+    };
 
-        let url = url::Url::parse(&ep)?;
-        let nonce = random_number.to_string();
-        debug!(
-            "Signing service: method:{} payload_hash:{} region:{} nonce:{}",
-            method, payload_hash, region, nonce
-        );
-        let sig = match signature(
-            &url,
-            method,
-            &key,
-            &secret,
-            region,
-            &service,
-            &machineid,
-            &hostname,
-            payload_hash,
-            &nonce,
-        ) {
-            Ok(s) => s,
-            Err(e) => return Err(anyhow::anyhow!("Signature Creation Failure {}", e)),
-        };
+    let signature = match (&options.signature_keys, signature) {
+        (Some(_), None) => verify::fetch_signature(client, ep, &signer).await?,
+        (_, signature) => signature,
+    };
+    if let Some(keys) = &options.signature_keys {
+        let verified = verify::Keyring::load(std::path::Path::new(keys)).and_then(|keyring| {
+            keyring
+                .verify(resptext.as_bytes(), signature.as_deref())
+                .map(str::to_owned)
+        });
+        match verified {
+            Ok(key) => debug!("Config signed by {}", key),
+            Err(e) => {
+                warn!("Refusing config from {}: {}", ep, e);
+                state.record("configRejected", ep, Some(e.to_string()));
+                return Err(BrogError::auth(format!(
+                    "Config signature verification failed: {}",
+                    e
+                ))
+                .into());
+            }
+        }
+    }
 
-        let sigdatetime = HeaderValue::from_str(&sig.date_time)?;
-        let sigauth = HeaderValue::from_str(&sig.auth_header)?;
-        let machinevalue = HeaderValue::from_str(machineid.as_str().trim())?;
-        let hostnamevalue = HeaderValue::from_str(hostname.as_str().trim())?;
-        let noncevalue = HeaderValue::from_str(&nonce)?;
-        headers.insert(
-            HeaderName::from_static("x-mhl-content-sha256"),
-            HeaderValue::from_static(payload_hash),
-        );
+    let document = BrogDocument::from_yaml(&resptext)?;
+    debug!("Response YAML:{:?}", document);
+    if let Some((etag, last_modified)) = validators {
+        state.config_cache = Some(state::ConfigCache {
+            url: ep.to_owned(),
+            etag,
+            last_modified,
+            body: resptext.clone(),
+            signature,
+        });
+    }
+    for field in document.unknown_fields() {
+        warn!("Ignoring unknown brog.yaml field: {}", field);
+    }
 
-        headers.insert(HeaderName::from_static("x-mhl-date"), sigdatetime);
-        headers.insert(AUTHORIZATION, sigauth);
-        headers.insert(HeaderName::from_static("x-mhl-mid"), machinevalue);
-        headers.insert(HeaderName::from_static("x-mhl-hostname"), hostnamevalue);
-        headers.insert(HeaderName::from_static("x-mhl-nonce"), noncevalue);
+    let clientconfig = match document.client() {
+        Some(c) => c,
+        None => return Err(BrogError::config("Invalid brog.yaml: clientConfig is empty").into()),
+    };
+    let requiredimage = clientconfig.image.as_str();
+    debug!("Setting image:{}", requiredimage);
+    state.target_image = Some(requiredimage.to_owned());
 
-        if Path::new(&shapath).exists() {
-            let shacontents = fs::read_to_string(&shapath)?;
-            let shavalue = HeaderValue::from_str(&shacontents)?;
-            debug!("Setting x-clos-commit: {}", shacontents);
-            headers.insert(HeaderName::from_static("x-clos-commit"), shavalue);
+    if let Some(policy) = options
+        .policy_path
+        .as_deref()
+        .map(|path| policy::ImagePolicy::load(std::path::Path::new(path)))
+        .transpose()?
+        .flatten()
+    {
+        if let Err(e) = policy.check(requiredimage) {
+            warn!("{}", e);
+            state.record("policyRejected", requiredimage, Some(e.to_string()));
+            return Err(e);
         }
     }
 
-    debug!("Sending Headers:{:#?}", headers);
+    let mut host = driver.status().await?;
+    let booted_digest = host
+        .booted_image()
+        .filter(|b| b.matches(requiredimage))
+        .map(|b| b.image_digest.clone());
+
+    let default_registry;
+    let registry: &dyn registry::RegistryClient = match &options.registry {
+        Some(registry) => registry.as_ref(),
+        None => {
+            default_registry = registry::HttpRegistryClient::with_client(client.clone());
+            &default_registry
+        }
+    };
+    let resolved = match clientconfig.resolve_digest {
+        true => {
+            let reference = image::ImageRef::parse(requiredimage)?;
+            match &reference.digest {
+                // Pinned references need no resolving.
+                Some(_) => None,
+                None => Some(registry.resolve(&reference).await?),
+            }
+        }
+        false => None,
+    };
+    if let Some(digest) = &resolved {
+        debug!("{} resolves to {}", requiredimage, digest);
+    }
+    // The digest the booted image should be updated to in place, if any.
+    let mut update = match (clientconfig.update_policy, &booted_digest, &resolved) {
+        (config::UpdatePolicy::Pinned, _, _) | (_, None, _) => None,
+        (_, Some(booted), Some(digest)) => Some(digest.clone()).filter(|d| d != booted),
+        (config::UpdatePolicy::Upgrade, Some(booted), None) => {
+            debug!("Checking for an upgrade of {}", requiredimage);
+            let output = driver.upgrade(driver::UpgradeMode::Check).await?;
+            debug!("bootc output:{}", output.stdout);
+            host = driver.status().await?;
+            host.status
+                .booted
+                .as_ref()
+                .and_then(|b| b.cached_update.as_ref())
+                .map(|cached| cached.image_digest.clone())
+                .filter(|d| d != booted)
+        }
+        (config::UpdatePolicy::Switch, Some(_), None) => None,
+    };
+    let mut target_digest = match clientconfig.update_policy {
+        config::UpdatePolicy::Pinned => None,
+        _ => update.clone().or_else(|| resolved.clone()),
+    };
 
-    let res = client.get(ep.clone()).headers(headers).send().await?;
-    if res.status() != reqwest::StatusCode::OK {
-        Err(anyhow::anyhow!("Invalid request: {}, {}", res.status(), ep))
+    let plan = |outcome: Outcome, reason: String| {
+        let (switch, apply) = outcome.changes();
+        let plan = Plan {
+            from: host
+                .booted_image()
+                .map(|b| format!("{}@{}", b.image.image, b.image_digest)),
+            to: requiredimage.to_owned(),
+            switch,
+            apply,
+            reason,
+            dry_run: options.dry_run,
+        };
+        (outcome, plan)
+    };
+    let windows = options
+        .maintenance_windows
+        .as_deref()
+        .unwrap_or(&clientconfig.maintenance_windows);
+    // What the reboot into a new image is waiting for, if anything.
+    let hold = if options.stage_only {
+        Some("brog apply")
+    } else if !window::is_open(windows, chrono::Utc::now()) {
+        Some("a maintenance window")
     } else {
-        let sha = res.headers().get("x-clos-commit");
-        if let Some(commit) = sha {
-            debug!("Writing shafile: {}", shapath);
-            let mut f = std::fs::OpenOptions::new()
-                .write(true)
-                .create(true)
-                .truncate(true)
-                .open(&shapath)?;
-            f.write_all(commit.as_bytes())?;
-            f.flush()?;
-        }
-        let resptext = res.text().await?;
-        let data: serde_yaml::Value = serde_yaml::from_str(&resptext)?;
-        debug!("Response YAML:{:?}", data);
-
-        let image = data["clientConfig"][0]["image"].as_str();
-
-        let requiredimage = if let Some(i) = image {
-            i
-        } else {
-            let mapimage = data["clientConfig"]["image"].as_str();
-            if let Some(i) = mapimage {
-                i
-            } else {
-                return Err(anyhow::anyhow!(
-                    "clientConfig-image is not a string {:?}",
-                    image
-                ));
+        None
+    };
+    let attempts = clientconfig.retrycount.unwrap_or(0) + 1;
+
+    // A deployment is current when it is the target and, if a newer digest
+    // is known, has that digest.
+    let current = |status: &bootc::ImageStatus| {
+        status.matches(requiredimage)
+            && target_digest
+                .as_ref()
+                .is_none_or(|digest| *digest == status.image_digest)
+    };
+
+    if let Some(booted) = host.booted_image().filter(|b| b.matches(requiredimage)) {
+        match &update {
+            None => {
+                debug!("Already booted on {}", requiredimage);
+                state.applied_image = Some(requiredimage.to_owned());
+                state.applied_digest = Some(booted.image_digest.clone());
+                state.attempts = 0;
+                return Ok(plan(Outcome::NoChange, "already booted".to_owned()));
+            }
+            Some(digest) => info!(
+                "{} moved from {} to {}",
+                requiredimage, booted.image_digest, digest
+            ),
+        }
+    }
+
+    if let Some(staged) = host.staged_image().filter(|s| current(s)) {
+        debug!("Already staged {}", requiredimage);
+        if let Some(hold) = hold {
+            let reason = format!("staged, waiting for {}", hold);
+            return Ok(plan(
+                Outcome::Deferred {
+                    reason: reason.clone(),
+                },
+                reason,
+            ));
+        }
+        match moved_since_staged(driver, staged).await? {
+            // The new content goes through the same checks as any other
+            // target before it is booted.
+            Some(digest) => {
+                info!(
+                    "{} moved from {} to {} since it was staged",
+                    requiredimage, staged.image_digest, digest
+                );
+                update = Some(digest.clone());
+                target_digest = Some(digest);
+            }
+            None => {
+                refuse_downgrade(
+                    &host,
+                    requiredimage,
+                    target_digest.as_deref(),
+                    clientconfig,
+                    registry,
+                    state,
+                )
+                .await?;
+                // Nothing holds the reboot back any more, e.g. the windows were
+                // removed or stage-only was turned off since staging.
+                let reason = match windows.is_empty() {
+                    true => "applying staged deployment",
+                    false => "maintenance window is open",
+                };
+                if options.dry_run {
+                    return Ok(plan(Outcome::Applied, reason.to_owned()));
+                }
+                // The tag has not moved, so upgrade only reboots into the
+                // staged deployment.
+                store.save(state)?;
+                bootc_with_retries(
+                    driver,
+                    Change::Upgrade,
+                    true,
+                    requiredimage,
+                    "applied",
+                    attempts,
+                    store,
+                    state,
+                )
+                .await?;
+                return Ok(plan(Outcome::Applied, reason.to_owned()));
             }
+        }
+    }
+
+    if state.is_rolled_back(requiredimage, target_digest.as_deref()) {
+        warn!(
+            "Not switching to {}: it was rolled back after failing health checks",
+            requiredimage
+        );
+        let reason = "it was rolled back after failing health checks".to_owned();
+        return Ok(plan(
+            Outcome::Rejected {
+                policy: "healthCheck".to_owned(),
+                reason: reason.clone(),
+            },
+            reason,
+        ));
+    }
+
+    refuse_downgrade(
+        &host,
+        requiredimage,
+        target_digest.as_deref(),
+        clientconfig,
+        registry,
+        state,
+    )
+    .await?;
+
+    if !clientconfig.canary_schedule.is_empty() {
+        let now = chrono::Utc::now();
+        // A moved tag starts a new rollout.
+        let rollout_image = match &target_digest {
+            Some(digest) => format!("{}@{}", requiredimage, digest),
+            None => requiredimage.to_owned(),
         };
-        debug!("Setting image:{}", requiredimage);
+        let rollout = canary::Rollout::for_image(state.rollout.as_ref(), &rollout_image, now);
+        let percent = rollout.percent(
+            &clientconfig.canary_schedule,
+            clientconfig.interval.unwrap_or(0),
+            now,
+        );
+        let bucket = canary::machine_bucket(&machineid);
+        debug!(
+            "Canary rollout started:{} percent:{} bucket:{}",
+            rollout.started, percent, bucket
+        );
+        state.rollout = Some(rollout);
+        state.canary_percent = Some(percent);
+        if bucket >= percent {
+            info!(
+                "Deferring {}: canary stage covers {}% of devices, this device is in bucket {}",
+                requiredimage, percent, bucket
+            );
+            let reason = format!(
+                "canary stage covers {}% of devices, this device is in bucket {}",
+                percent, bucket
+            );
+            return Ok(plan(
+                Outcome::Deferred {
+                    reason: reason.clone(),
+                },
+                reason,
+            ));
+        }
+    }
+
+    let reason = match (hold, &update) {
+        (Some(hold), _) => format!("waiting for {}", hold),
+        (None, Some(digest)) => format!("tag moved to {}", digest),
+        (None, None) => "target differs from booted image".to_owned(),
+    };
+    let outcome = match hold {
+        Some(_) => Outcome::Staged,
+        None => Outcome::Applied,
+    };
+    if options.dry_run {
+        return Ok(plan(outcome, reason));
+    }
+
+    // --apply reboots, so the pending check must be recorded beforehand.
+    if let Some(check) = &clientconfig.health_check {
+        state.pending_check = Some(health::PendingCheck {
+            image: requiredimage.to_owned(),
+            digest: target_digest.clone(),
+            check: check.clone(),
+            boots: 0,
+            boot_id: None,
+        });
+    }
+    store.save(state)?;
+
+    // A moved tag is already the booted or staged image reference, so bootc
+    // upgrade pulls the new content.
+    let (change, mut event) = if update.is_some() {
+        (Change::Upgrade, "upgraded")
+    } else {
+        (Change::Switch(requiredimage), "switched")
+    };
+    if let Some(hold) = hold {
+        info!(
+            "Staging {} without rebooting, waiting for {}",
+            requiredimage, hold
+        );
+        event = "staged";
+    }
+    bootc_with_retries(
+        driver,
+        change,
+        hold.is_none(),
+        requiredimage,
+        event,
+        attempts,
+        store,
+        state,
+    )
+    .await?;
+    if target_digest.is_some() {
+        state.applied_digest = target_digest;
+    }
+    Ok(plan(outcome, reason))
+}
 
-        let args = vec!["switch", requiredimage, "--apply"];
-        info!("Updating: {:?}", args);
-        let text = run_command_text(args, bin_path.as_str())?;
-        debug!("bootc output:{}", text);
-        Ok(requiredimage.to_owned())
+/// Fails when `image` is known to be older than the booted image and the
+/// config does not set `allowDowngrade`.
+async fn refuse_downgrade(
+    host: &bootc::BootcHost,
+    image: &str,
+    digest: Option<&str>,
+    clientconfig: &ClientConfig,
+    registry: &dyn registry::RegistryClient,
+    state: &mut State,
+) -> Result<(), anyhow::Error> {
+    let booted = match host.booted_image().and_then(|b| b.version.as_deref()) {
+        Some(version) => version,
+        None => return Ok(()),
+    };
+    let labelled;
+    let target = match host.version_of(image, digest) {
+        Some(version) => version,
+        None if clientconfig.allow_downgrade => return Ok(()),
+        // Only a config that already asks the registry about its image has
+        // the version label looked up.
+        None if !clientconfig.resolve_digest => {
+            warn!(
+                "Version of {} is unknown, downgrade protection was not applied. Set resolveDigest: true to read it from the registry",
+                image
+            );
+            return Ok(());
+        }
+        None => {
+            // A moved tag is labelled by its new digest.
+            let mut reference = image::ImageRef::parse(image)?;
+            if let Some(digest) = digest {
+                reference.digest = Some(digest.to_owned());
+            }
+            labelled = match registry.version(&reference).await {
+                Ok(Some(version)) => version,
+                Ok(None) => {
+                    warn!(
+                        "{} has no {} label, downgrade protection was not applied",
+                        image,
+                        registry::VERSION_LABEL
+                    );
+                    return Ok(());
+                }
+                Err(e) => {
+                    warn!(
+                        "Cannot read the version of {}, downgrade protection was not applied: {}",
+                        image, e
+                    );
+                    return Ok(());
+                }
+            };
+            &labelled
+        }
+    };
+    if !version::is_downgrade(booted, target) {
+        return Ok(());
+    }
+    if clientconfig.allow_downgrade {
+        info!(
+            "Downgrading from {} to {} ({}), allowed by allowDowngrade",
+            booted, target, image
+        );
+        return Ok(());
     }
+    let e = BrogError::PolicyRejected {
+        image: image.to_owned(),
+        policy: "allowDowngrade".to_owned(),
+        reason: format!(
+            "Refusing to downgrade from version {} to {} ({}), set allowDowngrade: true to allow it",
+            booted, target, image
+        ),
+    };
+    warn!("{}", e);
+    state.record("downgradeRefused", image, Some(e.to_string()));
+    Err(e.into())
 }
 
-pub fn run_command_text(args: Vec<&str>, bin_path: &str) -> Result<String, anyhow::Error> {
-    debug!("running {:?} {:?}", args, bin_path);
+/// The digest the tag of the `staged` deployment has moved to since it was
+/// staged, if it has. bootc cannot reboot into a staged deployment without
+/// upgrading, and `bootc upgrade --apply` pulls and boots a moved tag.
+pub(crate) async fn moved_since_staged(
+    driver: &dyn BootcDriver,
+    staged: &bootc::ImageStatus,
+) -> Result<Option<String>, anyhow::Error> {
+    if staged.image.image.contains('@') {
+        return Ok(None);
+    }
+    let output = driver.upgrade(driver::UpgradeMode::Check).await?;
+    debug!("bootc output:{}", output.stdout);
+    let host = driver.status().await?;
+    let moved = [host.status.booted.as_ref(), host.status.staged.as_ref()]
+        .into_iter()
+        .flatten()
+        .filter_map(|entry| entry.cached_update.as_ref())
+        .find(|update| update.image.image == staged.image.image)
+        .map(|update| update.image_digest.clone())
+        .filter(|digest| *digest != staged.image_digest);
+    Ok(moved)
+}
 
-    let cmd = Command::new("bootc")
-        .env("PATH", bin_path)
-        .stdout(Stdio::piped())
-        .stderr(Stdio::piped())
-        .args(&args)
-        .spawn()?;
+/// A bootc command that changes the deployment.
+#[derive(Debug, Clone, Copy)]
+pub(crate) enum Change<'a> {
+    Switch(&'a str),
+    Upgrade,
+}
 
-    let waiter = cmd.wait_with_output()?;
+impl Change<'_> {
+    fn verb(&self) -> &'static str {
+        match self {
+            Change::Switch(_) => "switch",
+            Change::Upgrade => "upgrade",
+        }
+    }
+}
 
-    let mut err_str = String::new();
-    waiter.stderr.as_slice().read_to_string(&mut err_str)?;
-    if !err_str.is_empty() {
-        let err = format!(
-            "stderr not empty - failed to execute bootc {:?} {}",
-            args, err_str
+/// Runs a bootc command that changes the deployment, retrying it up to
+/// `attempts` times and recording the outcome in `state`. The state is saved
+/// between attempts, saving the outcome is up to the caller. `apply` reboots
+/// into the new deployment.
+#[allow(clippy::too_many_arguments)]
+pub(crate) async fn bootc_with_retries(
+    driver: &dyn BootcDriver,
+    change: Change<'_>,
+    apply: bool,
+    image: &str,
+    event: &str,
+    attempts: u32,
+    store: &StateStore,
+    state: &mut State,
+) -> Result<(), anyhow::Error> {
+    let mut attempt = 1;
+    loop {
+        info!(
+            "Updating ({}/{}): {:?} apply:{}",
+            attempt, attempts, change, apply
         );
-        return Err(std::io::Error::new(std::io::ErrorKind::InvalidData, err).into());
+        let result = match change {
+            Change::Switch(image) => driver.switch(image, apply).await,
+            Change::Upgrade if apply => driver.upgrade(driver::UpgradeMode::Apply).await,
+            Change::Upgrade => driver.upgrade(driver::UpgradeMode::Stage).await,
+        };
+        match result {
+            Ok(output) => {
+                debug!("bootc output:{}", output.stdout);
+                state.applied_image = Some(image.to_owned());
+                state.applied_digest = None;
+                state.attempts = 0;
+                state.record(event, image, None);
+                return Ok(());
+            }
+            Err(e) => {
+                state.attempts += 1;
+                if attempt < attempts {
+                    warn!("bootc {} failed, retrying: {}", change.verb(), e);
+                    store.save(state)?;
+                    attempt += 1;
+                    tokio::time::sleep(RETRY_DELAY).await;
+                } else {
+                    state.pending_check = None;
+                    state.record(
+                        &format!("{}Failed", change.verb()),
+                        image,
+                        Some(e.to_string()),
+                    );
+                    return Err(e.into());
+                }
+            }
+        }
     }
-    let mut ok_str = String::new();
-    match waiter.stdout.as_slice().read_to_string(&mut ok_str) {
-        Err(e) => Err(e.into()),
-        Ok(_) => Ok(ok_str),
+}
+
+/// Runs bootc and returns its stdout. Anything bootc printed on stderr is
+/// logged as a warning, failure is decided by the exit status alone.
+pub fn run_command_text(args: Vec<&str>, bin_path: &str) -> Result<String, BrogError> {
+    let output = run_command(&args, bin_path)?;
+    if !output.stderr.trim().is_empty() {
+        warn!("bootc {:?}: {}", args, output.stderr.trim());
     }
+    Ok(output.stdout)
 }
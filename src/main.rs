@@ -1,25 +1,75 @@
 // SPDX-License-Identifier: MIT
 // Copyright 2024 brog Authors
 
-use brog::process;
+use brog::{
+    inspect,
+    metrics::{self, Metrics},
+    settings::DEFAULT_POLICY_PATH,
+    splay::Splay,
+    Agent, BrogConfig, BrogError, CommandDriver, Outcome,
+};
+use clap::{Parser, Subcommand};
 use dotenvy::EnvLoader;
 use std::result::Result::Ok;
+use std::sync::Arc;
 use std::time::Duration;
 use std::{env, str::FromStr};
 use tokio_cron_scheduler::{Job, JobScheduler};
-use tracing::{debug, error, Level};
+use tracing::{debug, error, info, warn, Level};
 use tracing_subscriber::FmtSubscriber;
 
+const VERSION: &str = env!("CARGO_PKG_VERSION");
+
+/// brog Server Edition - a gitops client for bootc.
+///
+/// See documentation https://github.com/ubiquitous-factory/brog
+#[derive(Parser)]
+#[command(name = "brog", version)]
+struct Cli {
+    /// Report what would be switched without calling bootc switch, same as DRY_RUN=true
+    #[arg(long, global = true)]
+    dry_run: bool,
+    /// Stage new images without rebooting into them until `brog apply`, same as STAGE_ONLY=true
+    #[arg(long, global = true)]
+    stage_only: bool,
+    #[command(subcommand)]
+    command: Option<Commands>,
+}
+
+#[derive(Subcommand)]
+enum Commands {
+    /// Run the agent on SCHEDULE (the default when no command is given)
+    Run,
+    /// Run a single reconciliation and exit
+    Once,
+    /// Reboot into the staged image
+    Apply,
+    /// Show the booted, staged and target image and the agent state
+    Status {
+        #[arg(long)]
+        json: bool,
+    },
+    /// Validate a brog.yaml file or URL without acting on it
+    CheckConfig {
+        /// A path or an http(s) URL
+        location: String,
+    },
+    /// Show recent switches, failures and rollbacks
+    History {
+        #[arg(long)]
+        json: bool,
+    },
+    /// Print the brog version
+    Version {
+        #[arg(long)]
+        json: bool,
+    },
+}
+
 #[dotenvy::load(path = "/etc/brog/.config", required = false, override_ = false)]
 #[tokio::main]
 async fn main() -> Result<(), anyhow::Error> {
-    if env::args().collect::<Vec<_>>().len() != 1 {
-        const VERSION: &str = env!("CARGO_PKG_VERSION");
-        println!("brog Server Edition v{}", VERSION);
-        println!("This process accepts no arguments.");
-        println!("See documentation https://github.com/ubiquitous-factory/brog");
-        return Ok(());
-    }
+    let cli = Cli::parse();
     if cfg!(debug_assertions) {
         let _ = EnvLoader::new();
     }
@@ -32,23 +82,112 @@ async fn main() -> Result<(), anyhow::Error> {
     let subscriber = FmtSubscriber::builder().with_max_level(log_level).finish();
     tracing::subscriber::set_global_default(subscriber).expect("Setting default subscriber failed");
 
-    let schedule = std::env::var("SCHEDULE").expect("ENDPOINT environment variable must be set");
+    // Only the commands that need the settings read them, so a bad
+    // variable does not break `brog version` or `brog check-config`.
+    let config = || -> Result<BrogConfig, anyhow::Error> {
+        let mut config = BrogConfig::from_env()?;
+        config.dry_run |= cli.dry_run;
+        config.stage_only |= cli.stage_only;
+        Ok(config)
+    };
+    match cli.command.unwrap_or(Commands::Run) {
+        Commands::Run => run(Agent::new(config()?)?).await,
+        Commands::Once => {
+            let agent = Agent::new(config()?)?;
+            if !agent.config().dry_run {
+                verify_health(&agent).await;
+            }
+            let outcome = agent.reconcile().await?;
+            println!("{}", outcome);
+            Ok(())
+        }
+        Commands::Apply => {
+            let plan = Agent::new(config()?)?.apply_staged().await?;
+            println!("{}", plan);
+            Ok(())
+        }
+        Commands::Status { json } => {
+            let config = config()?;
+            let driver = CommandDriver::new(&config.bin_path);
+            print!("{}", inspect::status(&config, &driver, json).await?);
+            Ok(())
+        }
+        Commands::CheckConfig { location } => {
+            let policy_path =
+                env::var("IMAGE_POLICY").unwrap_or_else(|_| DEFAULT_POLICY_PATH.to_owned());
+            print!(
+                "{}",
+                inspect::check_config(&location, Some(&policy_path)).await?
+            );
+            Ok(())
+        }
+        Commands::History { json } => {
+            print!("{}", inspect::history(&config()?, json)?);
+            Ok(())
+        }
+        Commands::Version { json } => {
+            if json {
+                println!(
+                    "{}",
+                    serde_json::json!({ "name": "brog", "version": VERSION })
+                );
+            } else {
+                println!("brog Server Edition v{}", VERSION);
+            }
+            Ok(())
+        }
+    }
+}
+
+async fn verify_health(agent: &Agent) {
+    match agent.verify_health().await {
+        Ok(outcome) => debug!("health check outcome: {:?}", outcome),
+        Err(e) => error!("health check error: {}", e),
+    }
+}
+
+async fn run(mut agent: Agent) -> Result<(), anyhow::Error> {
+    let schedule = agent
+        .config()
+        .schedule
+        .clone()
+        .ok_or_else(|| BrogError::config("SCHEDULE must be set for brog run"))?;
+    if let Some(addr) = agent.config().metrics_addr.clone() {
+        let metrics = Arc::new(Metrics::default());
+        metrics::listen(&addr, metrics.clone()).await?;
+        agent = agent.with_metrics(metrics);
+    }
+    let config = agent.config();
+    let splay = config.splay;
+    let machineid = std::fs::read_to_string(&config.machine_id_path).unwrap_or_default();
+    if matches!(splay, Splay::Machine(_)) && machineid.trim().is_empty() {
+        warn!("No machine-id found, SCHEDULE_SPLAY offsets will not be spread");
+    }
+
+    if !config.dry_run {
+        verify_health(&agent).await;
+    }
+    let agent = Arc::new(agent);
 
     let sched = JobScheduler::new().await?;
 
     sched
-        .add(Job::new_async(schedule, |uuid, mut l| {
+        .add(Job::new_async(schedule, move |uuid, mut l| {
+            let agent = agent.clone();
+            let delay = splay.delay(&machineid);
             Box::pin(async move {
-                let key = std::env::var("SERVICE_KEY").unwrap_or_default();
-                let secret = std::env::var("SERVICE_SECRET").unwrap_or_default();
-                let ep = std::env::var("ENDPOINT").expect("ENDPOINT Not Configured");
-                let servicename =
-                    std::env::var("SERVICE_NAME").unwrap_or_else(|_| "projects".to_string());
-                let bin_path = std::env::var("BIN_PATH").unwrap_or("/usr/bin:/bin/sbin".to_owned());
-                let service_location =
-                    std::env::var("CONFIG_PATH").unwrap_or("/etc/brog".to_owned());
-                match process(ep, key, secret, bin_path, servicename, service_location).await {
-                    Ok(_) => {}
+                if !delay.is_zero() {
+                    debug!("Splaying fetch by {:?}", delay);
+                    tokio::time::sleep(delay).await;
+                }
+                match agent.try_reconcile().await {
+                    Ok(None) => {
+                        warn!("Skipping tick, the previous reconciliation is still running")
+                    }
+                    Ok(Some(outcome)) if outcome.outcome == Outcome::NoChange => {
+                        debug!("{}", outcome)
+                    }
+                    Ok(Some(outcome)) => info!("{}", outcome),
                     Err(e) => {
                         error!("process execution error: {}", e);
                     }
@@ -0,0 +1,420 @@
+// SPDX-License-Identifier: MIT
+// Copyright 2024 brog Authors
+
+//! Prometheus metrics for a running agent.
+//!
+//! [`Metrics`] collects what the agent does: config fetches, bootc commands
+//! and the outcome of each reconciliation. [`listen`] serves them in the
+//! Prometheus text format on `/metrics`, so a node exporter or Prometheus on
+//! the device can scrape `METRICS_ADDR`. Values taken from the state file,
+//! such as the last successful reconciliation and the rollbacks, survive a
+//! restart.
+
+use crate::{
+    bootc::BootcHost,
+    command::CommandOutput,
+    driver::{BootcDriver, UpgradeMode},
+    error::BrogError,
+    outcome::ReconcileOutcome,
+    state::State,
+};
+use async_trait::async_trait;
+use std::{
+    collections::BTreeMap,
+    fmt::Write as _,
+    io,
+    net::SocketAddr,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::{TcpListener, TcpStream},
+};
+use tracing::{debug, info, warn};
+
+/// Upper bounds in seconds of the bootc command duration buckets. Pulling
+/// an image can take many minutes.
+const BOOTC_BUCKETS: [f64; 8] = [0.5, 1.0, 5.0, 15.0, 60.0, 300.0, 900.0, 1800.0];
+
+/// How long a scraper may take to send its request.
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(5);
+
+#[derive(Debug, Default)]
+pub struct Metrics {
+    inner: Mutex<Inner>,
+}
+
+#[derive(Debug, Default)]
+struct Inner {
+    fetch_attempts: u64,
+    fetch_failures: BTreeMap<&'static str, u64>,
+    fetch_responses: BTreeMap<u16, u64>,
+    bootc: BTreeMap<(&'static str, &'static str), Histogram>,
+    reconciles: BTreeMap<&'static str, u64>,
+    last_success: Option<i64>,
+    canary_percent: Option<u8>,
+    rollbacks: u64,
+    images: Option<Images>,
+}
+
+#[derive(Debug, Default)]
+struct Histogram {
+    buckets: [u64; BOOTC_BUCKETS.len()],
+    count: u64,
+    sum: f64,
+}
+
+#[derive(Debug)]
+struct Images {
+    booted_image: String,
+    booted_digest: String,
+    target_image: String,
+    current: bool,
+}
+
+impl Metrics {
+    fn lock(&self) -> std::sync::MutexGuard<'_, Inner> {
+        self.inner.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    /// Counts one config fetch attempt. `cached` is whether a
+    /// `304 Not Modified` can be answered from the cached config.
+    pub fn fetched(&self, result: &Result<reqwest::Response, reqwest::Error>, cached: bool) {
+        let mut inner = self.lock();
+        inner.fetch_attempts += 1;
+        let failure = match result {
+            Ok(res) => {
+                let status = res.status();
+                *inner.fetch_responses.entry(status.as_u16()).or_default() += 1;
+                let ok = status == reqwest::StatusCode::OK
+                    || (status == reqwest::StatusCode::NOT_MODIFIED && cached);
+                if ok {
+                    None
+                } else {
+                    Some("status")
+                }
+            }
+            Err(e) if e.is_timeout() => Some("timeout"),
+            Err(e) if e.is_connect() => Some("connect"),
+            Err(_) => Some("transport"),
+        };
+        if let Some(reason) = failure {
+            *inner.fetch_failures.entry(reason).or_default() += 1;
+        }
+    }
+
+    /// Records how long `bootc <command>` ran and whether it succeeded.
+    pub fn bootc_command(&self, command: &'static str, duration: Duration, ok: bool) {
+        let result = if ok { "success" } else { "failure" };
+        let mut inner = self.lock();
+        let histogram = inner.bootc.entry((command, result)).or_default();
+        let seconds = duration.as_secs_f64();
+        for (bucket, le) in histogram.buckets.iter_mut().zip(BOOTC_BUCKETS) {
+            if seconds <= le {
+                *bucket += 1;
+            }
+        }
+        histogram.count += 1;
+        histogram.sum += seconds;
+    }
+
+    /// Records the outcome of a reconciliation, the state it left behind and
+    /// the images of `host`.
+    pub fn reconciled(
+        &self,
+        result: &Result<ReconcileOutcome, BrogError>,
+        state: &State,
+        host: Option<&BootcHost>,
+    ) {
+        let outcome = match result {
+            Ok(outcome) => outcome.outcome.name(),
+            Err(_) => "error",
+        };
+        *self.lock().reconciles.entry(outcome).or_default() += 1;
+        self.observe_state(state);
+        let booted = host.and_then(|h| h.booted_image());
+        if let (Some(booted), Some(target)) = (booted, &state.target_image) {
+            self.lock().images = Some(Images {
+                booted_image: booted.image.image.clone(),
+                booted_digest: booted.image_digest.clone(),
+                target_image: target.clone(),
+                current: booted.matches(target),
+            });
+        }
+    }
+
+    /// Takes the values kept in the state file.
+    pub fn observe_state(&self, state: &State) {
+        let mut inner = self.lock();
+        inner.last_success = state.last_success.map(|at| at.timestamp());
+        inner.canary_percent = state.canary_percent;
+        inner.rollbacks = state.rollbacks.len() as u64;
+    }
+
+    /// The metrics in the Prometheus text format.
+    pub fn render(&self) -> String {
+        let inner = self.lock();
+        let mut out = String::new();
+        header(
+            &mut out,
+            "brog_fetch_attempts_total",
+            "counter",
+            "Config fetch attempts, including retries.",
+        );
+        let _ = writeln!(out, "brog_fetch_attempts_total {}", inner.fetch_attempts);
+
+        header(
+            &mut out,
+            "brog_fetch_failures_total",
+            "counter",
+            "Failed config fetch attempts by reason: timeout, connect, transport or status.",
+        );
+        for (reason, count) in &inner.fetch_failures {
+            let _ = writeln!(
+                out,
+                "brog_fetch_failures_total{{reason=\"{}\"}} {}",
+                reason, count
+            );
+        }
+
+        header(
+            &mut out,
+            "brog_fetch_responses_total",
+            "counter",
+            "Config fetch responses by HTTP status.",
+        );
+        for (status, count) in &inner.fetch_responses {
+            let _ = writeln!(
+                out,
+                "brog_fetch_responses_total{{status=\"{}\"}} {}",
+                status, count
+            );
+        }
+
+        header(
+            &mut out,
+            "brog_bootc_command_duration_seconds",
+            "histogram",
+            "How long bootc commands ran.",
+        );
+        for ((command, result), histogram) in &inner.bootc {
+            let labels = format!("command=\"{}\",result=\"{}\"", command, result);
+            for (le, count) in BOOTC_BUCKETS.iter().zip(histogram.buckets) {
+                let _ = writeln!(
+                    out,
+                    "brog_bootc_command_duration_seconds_bucket{{{},le=\"{}\"}} {}",
+                    labels, le, count
+                );
+            }
+            let _ = writeln!(
+                out,
+                "brog_bootc_command_duration_seconds_bucket{{{},le=\"+Inf\"}} {}",
+                labels, histogram.count
+            );
+            let _ = writeln!(
+                out,
+                "brog_bootc_command_duration_seconds_sum{{{}}} {}",
+                labels, histogram.sum
+            );
+            let _ = writeln!(
+                out,
+                "brog_bootc_command_duration_seconds_count{{{}}} {}",
+                labels, histogram.count
+            );
+        }
+
+        header(
+            &mut out,
+            "brog_reconciles_total",
+            "counter",
+            "Reconciliations by outcome, error when one failed.",
+        );
+        for (outcome, count) in &inner.reconciles {
+            let _ = writeln!(
+                out,
+                "brog_reconciles_total{{outcome=\"{}\"}} {}",
+                outcome, count
+            );
+        }
+
+        if let Some(at) = inner.last_success {
+            header(
+                &mut out,
+                "brog_last_success_timestamp_seconds",
+                "gauge",
+                "When the last reconciliation succeeded, in seconds since the epoch.",
+            );
+            let _ = writeln!(out, "brog_last_success_timestamp_seconds {}", at);
+        }
+
+        if let Some(percent) = inner.canary_percent {
+            header(
+                &mut out,
+                "brog_canary_stage_percent",
+                "gauge",
+                "The percentage of devices covered by the current canary stage.",
+            );
+            let _ = writeln!(out, "brog_canary_stage_percent {}", percent);
+        }
+
+        header(
+            &mut out,
+            "brog_rollbacks_total",
+            "counter",
+            "Deployments rolled back after failing health checks.",
+        );
+        let _ = writeln!(out, "brog_rollbacks_total {}", inner.rollbacks);
+
+        if let Some(images) = &inner.images {
+            header(
+                &mut out,
+                "brog_image_info",
+                "gauge",
+                "The booted and the target image.",
+            );
+            let _ = writeln!(
+                out,
+                "brog_image_info{{booted_image=\"{}\",booted_digest=\"{}\",target_image=\"{}\"}} 1",
+                escape(&images.booted_image),
+                escape(&images.booted_digest),
+                escape(&images.target_image)
+            );
+            header(
+                &mut out,
+                "brog_image_current",
+                "gauge",
+                "1 when the booted image is the target image.",
+            );
+            let _ = writeln!(out, "brog_image_current {}", images.current as u8);
+        }
+        out
+    }
+}
+
+fn header(out: &mut String, name: &str, kind: &str, help: &str) {
+    let _ = writeln!(out, "# HELP {} {}", name, help);
+    let _ = writeln!(out, "# TYPE {} {}", name, kind);
+}
+
+/// Escapes a label value.
+fn escape(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+/// Serves `metrics` on `http://<addr>/metrics` until the runtime shuts
+/// down, returning the address listened on.
+pub async fn listen(addr: &str, metrics: Arc<Metrics>) -> Result<SocketAddr, anyhow::Error> {
+    let listener = TcpListener::bind(addr)
+        .await
+        .map_err(|e| anyhow::anyhow!("Cannot listen on {}: {}", addr, e))?;
+    let local = listener.local_addr()?;
+    info!("Serving metrics on http://{}/metrics", local);
+    tokio::spawn(async move {
+        loop {
+            match listener.accept().await {
+                Ok((stream, peer)) => {
+                    let metrics = metrics.clone();
+                    tokio::spawn(async move {
+                        if let Err(e) = respond(stream, &metrics).await {
+                            debug!("Metrics request from {} failed: {}", peer, e);
+                        }
+                    });
+                }
+                Err(e) => warn!("Cannot accept metrics connection: {}", e),
+            }
+        }
+    });
+    Ok(local)
+}
+
+async fn respond(mut stream: TcpStream, metrics: &Metrics) -> io::Result<()> {
+    let mut request = vec![0; 4096];
+    let mut len = 0;
+    let read = async {
+        while len < request.len() {
+            let n = stream.read(&mut request[len..]).await?;
+            len += n;
+            if n == 0 || request[..len].windows(4).any(|w| w == b"\r\n\r\n") {
+                break;
+            }
+        }
+        Ok::<_, io::Error>(())
+    };
+    tokio::time::timeout(REQUEST_TIMEOUT, read)
+        .await
+        .map_err(|_| io::Error::new(io::ErrorKind::TimedOut, "request timed out"))??;
+    let request = String::from_utf8_lossy(&request[..len]);
+    let mut words = request.split_whitespace();
+    let method = words.next();
+    let path = words.next().and_then(|p| p.split('?').next());
+    let (status, body) = match (method, path) {
+        (Some("GET"), Some("/metrics")) => ("200 OK", metrics.render()),
+        (Some("GET"), _) => ("404 Not Found", "Not Found\n".to_owned()),
+        _ => ("405 Method Not Allowed", "Method Not Allowed\n".to_owned()),
+    };
+    let response = format!(
+        "HTTP/1.1 {}\r\nContent-Type: text/plain; version=0.0.4; charset=utf-8\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        status,
+        body.len(),
+        body
+    );
+    stream.write_all(response.as_bytes()).await?;
+    stream.shutdown().await
+}
+
+/// A [`BootcDriver`] recording how long the commands of another driver take.
+#[derive(Debug)]
+pub(crate) struct MeteredDriver {
+    driver: Arc<dyn BootcDriver>,
+    metrics: Arc<Metrics>,
+}
+
+impl MeteredDriver {
+    pub(crate) fn new(driver: Arc<dyn BootcDriver>, metrics: Arc<Metrics>) -> Self {
+        MeteredDriver { driver, metrics }
+    }
+
+    fn record<T>(&self, command: &'static str, started: Instant, result: &Result<T, BrogError>) {
+        self.metrics
+            .bootc_command(command, started.elapsed(), result.is_ok());
+    }
+}
+
+#[async_trait]
+impl BootcDriver for MeteredDriver {
+    async fn status(&self) -> Result<BootcHost, BrogError> {
+        let started = Instant::now();
+        let result = self.driver.status().await;
+        self.record("status", started, &result);
+        result
+    }
+
+    async fn switch(&self, image: &str, apply: bool) -> Result<CommandOutput, BrogError> {
+        let started = Instant::now();
+        let result = self.driver.switch(image, apply).await;
+        self.record("switch", started, &result);
+        result
+    }
+
+    async fn upgrade(&self, mode: UpgradeMode) -> Result<CommandOutput, BrogError> {
+        let started = Instant::now();
+        let result = self.driver.upgrade(mode).await;
+        self.record("upgrade", started, &result);
+        result
+    }
+
+    async fn rollback(&self, apply: bool) -> Result<CommandOutput, BrogError> {
+        let started = Instant::now();
+        let result = self.driver.rollback(apply).await;
+        self.record("rollback", started, &result);
+        result
+    }
+
+    async fn kargs(&self) -> Result<Vec<String>, BrogError> {
+        self.driver.kargs().await
+    }
+}
@@ -0,0 +1,120 @@
+// SPDX-License-Identifier: MIT
+// Copyright 2024 brog Authors
+
+//! What a reconciliation did.
+//!
+//! [`crate::Agent::reconcile`] returns a [`ReconcileOutcome`] for every tick
+//! that reached a decision, including a target refused by a local policy.
+//! Errors are kept for ticks that could not decide, e.g. because the config
+//! could not be fetched or bootc failed.
+
+use crate::Plan;
+use chrono::{DateTime, Utc};
+use std::{fmt, time::Duration};
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Outcome {
+    /// The target is already booted, or staged with nothing to wait for.
+    NoChange,
+    /// The target was staged and is not booted until its hold is lifted.
+    Staged,
+    /// The host was switched or upgraded to the target and rebooted.
+    Applied,
+    /// The target failed its health checks and the host was rolled back to
+    /// the previous deployment, see [`crate::Agent::verify_health`].
+    RolledBack,
+    /// The target is not acted on yet, e.g. outside the canary stage or
+    /// while a staged deployment waits for a maintenance window.
+    Deferred { reason: String },
+    /// A local rule refused the target. `policy` names the rule, e.g.
+    /// `imagePolicy`, `allowDowngrade` or `healthCheck` for a target that
+    /// was rolled back before.
+    Rejected { policy: String, reason: String },
+}
+
+impl Outcome {
+    /// A camelCase name for reports and metrics, e.g. `noChange`.
+    pub fn name(&self) -> &'static str {
+        match self {
+            Outcome::NoChange => "noChange",
+            Outcome::Staged => "staged",
+            Outcome::Applied => "applied",
+            Outcome::RolledBack => "rolledBack",
+            Outcome::Deferred { .. } => "deferred",
+            Outcome::Rejected { .. } => "rejected",
+        }
+    }
+
+    /// Whether the host was switched, and whether it was rebooted.
+    pub(crate) fn changes(&self) -> (bool, bool) {
+        match self {
+            Outcome::Applied => (true, true),
+            Outcome::Staged => (true, false),
+            _ => (false, false),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct ReconcileOutcome {
+    pub outcome: Outcome,
+    /// The booted image as `image@digest` when the reconciliation started.
+    pub previous_image: Option<String>,
+    pub target_image: String,
+    /// The `x-clos-commit` of the config that was acted upon.
+    pub commit: Option<String>,
+    pub reason: String,
+    /// Nothing was changed, the outcome is what would have happened.
+    pub dry_run: bool,
+    pub started_at: DateTime<Utc>,
+    pub duration: Duration,
+}
+
+impl ReconcileOutcome {
+    pub(crate) fn new(
+        outcome: Outcome,
+        plan: Plan,
+        commit: Option<String>,
+        started_at: DateTime<Utc>,
+        duration: Duration,
+    ) -> Self {
+        ReconcileOutcome {
+            outcome,
+            previous_image: plan.from,
+            target_image: plan.to,
+            commit,
+            reason: plan.reason,
+            dry_run: plan.dry_run,
+            started_at,
+            duration,
+        }
+    }
+
+    /// Whether the host was switched to the target, with or without a reboot.
+    pub fn is_change(&self) -> bool {
+        self.outcome.changes().0
+    }
+
+    pub fn plan(&self) -> Plan {
+        let (switch, apply) = self.outcome.changes();
+        Plan {
+            from: self.previous_image.clone(),
+            to: self.target_image.clone(),
+            switch,
+            apply,
+            reason: self.reason.clone(),
+            dry_run: self.dry_run,
+        }
+    }
+}
+
+impl fmt::Display for ReconcileOutcome {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.outcome {
+            Outcome::Rejected { reason, .. } => {
+                write!(f, "rejected {}: {}", self.target_image, reason)
+            }
+            _ => write!(f, "{}", self.plan()),
+        }
+    }
+}
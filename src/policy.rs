@@ -0,0 +1,101 @@
+// SPDX-License-Identifier: MIT
+// Copyright 2024 brog Authors
+
+//! A local allow-list for the images brog may switch to.
+//!
+//! The policy lives on the device, by default in `/etc/brog/policy.yaml`,
+//! so that a mistake in the gitops repository cannot move a fleet onto an
+//! unrelated image. It is checked before any switch.
+//!
+//! ```yaml
+//! allowedRegistries: [quay.io]
+//! allowedRepositories: [quay.io/fedora/fedora-bootc, quay.io/mehal_tech/*]
+//! requireDigest: false
+//! forbiddenTags: [latest]
+//! ```
+
+use crate::{error::BrogError, image::ImageRef};
+use serde::{Deserialize, Serialize};
+use std::{fs, path::Path};
+
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default, rename_all = "camelCase", deny_unknown_fields)]
+pub struct ImagePolicy {
+    /// Registries images may come from. Any registry when empty.
+    pub allowed_registries: Vec<String>,
+    /// `registry/repository` names, a trailing `*` matches any suffix.
+    /// Any repository when empty.
+    pub allowed_repositories: Vec<String>,
+    /// Only accept references pinned with `@sha256:...`.
+    pub require_digest: bool,
+    pub forbidden_tags: Vec<String>,
+}
+
+impl ImagePolicy {
+    /// Loads the policy at `path`, or `None` when there is no such file.
+    pub fn load(path: &Path) -> Result<Option<Self>, anyhow::Error> {
+        if !path.exists() {
+            return Ok(None);
+        }
+        let text = fs::read_to_string(path)?;
+        Self::from_yaml(&text).map(Some).map_err(|e| {
+            BrogError::config(format!("Invalid image policy {}: {}", path.display(), e)).into()
+        })
+    }
+
+    pub fn from_yaml(text: &str) -> Result<Self, anyhow::Error> {
+        Ok(serde_yaml::from_str(text)?)
+    }
+
+    /// Checks `image` against the policy, naming the rule it breaks.
+    pub fn check(&self, image: &str) -> Result<(), anyhow::Error> {
+        let reject = |reason: String| {
+            anyhow::Error::from(BrogError::PolicyRejected {
+                image: image.to_owned(),
+                policy: "imagePolicy".to_owned(),
+                reason: format!("Image policy rejects {}: {}", image, reason),
+            })
+        };
+        let reference = ImageRef::parse(image).map_err(|e| reject(e.to_string()))?;
+        if !self.allowed_registries.is_empty()
+            && !self.allowed_registries.contains(&reference.registry)
+        {
+            return Err(reject(format!(
+                "registry {} is not in allowedRegistries",
+                reference.registry
+            )));
+        }
+        let name = reference.name();
+        if !self.allowed_repositories.is_empty()
+            && !self
+                .allowed_repositories
+                .iter()
+                .any(|pattern| matches(pattern, &name))
+        {
+            return Err(reject(format!(
+                "repository {} is not in allowedRepositories",
+                name
+            )));
+        }
+        if self.require_digest && reference.digest.is_none() {
+            return Err(reject("a digest is required (image@sha256:...)".to_owned()));
+        }
+        // An unpinned reference without a tag means `latest`.
+        let tag = match (&reference.tag, &reference.digest) {
+            (Some(tag), _) => Some(tag.as_str()),
+            (None, None) => Some("latest"),
+            (None, Some(_)) => None,
+        };
+        if let Some(tag) = tag.filter(|t| self.forbidden_tags.iter().any(|f| f == t)) {
+            return Err(reject(format!("tag {} is forbidden", tag)));
+        }
+        Ok(())
+    }
+}
+
+fn matches(pattern: &str, name: &str) -> bool {
+    match pattern.strip_suffix('*') {
+        Some(prefix) => name.starts_with(prefix),
+        None => pattern == name,
+    }
+}
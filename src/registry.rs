@@ -0,0 +1,290 @@
+// SPDX-License-Identifier: MIT
+// Copyright 2024 brog Authors
+
+//! Resolving image tags to manifest digests.
+//!
+//! With `resolveDigest: true` in brog.yaml brog asks the registry which
+//! digest a tag currently points at. An unchanged digest means there is
+//! nothing to do; a moved tag means the booted image is out of date and
+//! `bootc upgrade` pulls the new content. It also reads the version label
+//! of a target bootc knows nothing about yet, for downgrade protection.
+//!
+//! [`RegistryClient`] is the extension point. [`HttpRegistryClient`] speaks
+//! the OCI distribution API, including the anonymous bearer token flow used
+//! by public registries.
+
+use crate::{error::BrogError, image::ImageRef};
+use async_trait::async_trait;
+use reqwest::{
+    header::{HeaderMap, HeaderValue, ACCEPT, AUTHORIZATION, WWW_AUTHENTICATE},
+    StatusCode,
+};
+use std::{fmt, time::Duration};
+use tracing::debug;
+
+const MANIFEST_TYPES: &str = "application/vnd.oci.image.index.v1+json, \
+application/vnd.oci.image.manifest.v1+json, \
+application/vnd.docker.distribution.manifest.list.v2+json, \
+application/vnd.docker.distribution.manifest.v2+json";
+
+/// The image config label holding the version, as `bootc status` shows it.
+pub const VERSION_LABEL: &str = "org.opencontainers.image.version";
+
+#[async_trait]
+pub trait RegistryClient: fmt::Debug + Send + Sync {
+    /// The digest, e.g. `sha256:...`, that `image`'s tag points at.
+    async fn resolve(&self, image: &ImageRef) -> Result<String, BrogError>;
+
+    /// The `org.opencontainers.image.version` label of `image`, if it has
+    /// one.
+    async fn version(&self, _image: &ImageRef) -> Result<Option<String>, BrogError> {
+        Ok(None)
+    }
+}
+
+/// A [`RegistryClient`] for OCI distribution registries. Registries on
+/// `localhost` or `127.0.0.1` are reached over plain http.
+#[derive(Debug, Clone)]
+pub struct HttpRegistryClient {
+    client: reqwest::Client,
+    /// Overrides the timeout of `client` when set.
+    timeout: Option<Duration>,
+}
+
+impl Default for HttpRegistryClient {
+    fn default() -> Self {
+        Self::new(Duration::from_secs(30))
+    }
+}
+
+impl HttpRegistryClient {
+    pub fn new(timeout: Duration) -> Self {
+        HttpRegistryClient {
+            client: reqwest::Client::new(),
+            timeout: Some(timeout),
+        }
+    }
+
+    /// Sends its requests with `client`, e.g. the one an [`crate::Agent`]
+    /// fetches configs with, and that client's timeout.
+    pub fn with_client(client: reqwest::Client) -> Self {
+        HttpRegistryClient {
+            client,
+            timeout: None,
+        }
+    }
+
+    fn request(&self, method: reqwest::Method, url: &str) -> reqwest::RequestBuilder {
+        let request = self.client.request(method, url);
+        match self.timeout {
+            Some(timeout) => request.timeout(timeout),
+            None => request,
+        }
+    }
+
+    /// `scheme://host/v2/repository` for `image`.
+    fn base_url(image: &ImageRef) -> String {
+        let host = match image.registry.as_str() {
+            "docker.io" => "registry-1.docker.io",
+            registry => registry,
+        };
+        let local = host.starts_with("localhost") || host.starts_with("127.0.0.1");
+        format!(
+            "{}://{}/v2/{}",
+            if local { "http" } else { "https" },
+            host,
+            image.repository
+        )
+    }
+
+    fn manifest_url(image: &ImageRef) -> String {
+        let reference = image.digest.as_deref().or(image.tag.as_deref());
+        format!(
+            "{}/manifests/{}",
+            Self::base_url(image),
+            reference.unwrap_or("latest")
+        )
+    }
+
+    /// Sends `method` to `url`, answering a `Bearer` challenge with an
+    /// anonymous token that is kept in `token` for later requests.
+    async fn send(
+        &self,
+        method: reqwest::Method,
+        url: &str,
+        accept: &'static str,
+        image: &ImageRef,
+        token: &mut Option<String>,
+    ) -> Result<reqwest::Response, BrogError> {
+        let mut headers = HeaderMap::new();
+        headers.insert(ACCEPT, HeaderValue::from_static(accept));
+        if let Some(token) = token.as_deref() {
+            headers.insert(AUTHORIZATION, bearer(token)?);
+        }
+        let mut res = self
+            .request(method.clone(), url)
+            .headers(headers.clone())
+            .send()
+            .await?;
+        if res.status() == StatusCode::UNAUTHORIZED && token.is_none() {
+            let challenge = res
+                .headers()
+                .get(WWW_AUTHENTICATE)
+                .and_then(|v| v.to_str().ok())
+                .unwrap_or_default()
+                .to_owned();
+            let new_token = self.token(&challenge, image).await?;
+            headers.insert(AUTHORIZATION, bearer(&new_token)?);
+            *token = Some(new_token);
+            res = self.request(method, url).headers(headers).send().await?;
+        }
+        if !res.status().is_success() {
+            return Err(BrogError::http_status(url, res.status()));
+        }
+        Ok(res)
+    }
+
+    async fn get_json(
+        &self,
+        url: &str,
+        accept: &'static str,
+        image: &ImageRef,
+        token: &mut Option<String>,
+    ) -> Result<serde_json::Value, BrogError> {
+        let res = self
+            .send(reqwest::Method::GET, url, accept, image, token)
+            .await?;
+        serde_json::from_str(&res.text().await?)
+            .map_err(|e| BrogError::parse("registry response", e))
+    }
+
+    /// Fetches an anonymous pull token for the `Bearer` challenge in
+    /// `challenge`.
+    async fn token(&self, challenge: &str, image: &ImageRef) -> Result<String, BrogError> {
+        let params = challenge.strip_prefix("Bearer ").ok_or_else(|| {
+            BrogError::auth(format!("Unsupported registry challenge {}", challenge))
+        })?;
+        let param = |name: &str| {
+            params.split(',').find_map(|p| {
+                let (key, value) = p.trim().split_once('=')?;
+                (key == name).then(|| value.trim_matches('"').to_owned())
+            })
+        };
+        let realm = param("realm").ok_or_else(|| {
+            BrogError::auth(format!("Registry challenge has no realm: {}", challenge))
+        })?;
+        let mut url = url::Url::parse(&realm).map_err(|e| {
+            BrogError::auth(format!(
+                "Registry challenge realm {:?} is not a URL: {}",
+                realm, e
+            ))
+        })?;
+        {
+            let mut query = url.query_pairs_mut();
+            if let Some(service) = param("service") {
+                query.append_pair("service", &service);
+            }
+            let scope =
+                param("scope").unwrap_or_else(|| format!("repository:{}:pull", image.repository));
+            query.append_pair("scope", &scope);
+        }
+        debug!("Requesting registry token from {}", url);
+        let res = self
+            .request(reqwest::Method::GET, url.as_str())
+            .send()
+            .await?;
+        if !res.status().is_success() {
+            return Err(BrogError::http_status(url.as_str(), res.status()));
+        }
+        let body: serde_json::Value = serde_json::from_str(&res.text().await?)
+            .map_err(|e| BrogError::parse("registry token response", e))?;
+        body.get("token")
+            .or_else(|| body.get("access_token"))
+            .and_then(|t| t.as_str())
+            .map(str::to_owned)
+            .ok_or_else(|| {
+                BrogError::parse("registry token response", format!("{} sent no token", url))
+            })
+    }
+}
+
+#[async_trait]
+impl RegistryClient for HttpRegistryClient {
+    async fn resolve(&self, image: &ImageRef) -> Result<String, BrogError> {
+        let url = Self::manifest_url(image);
+        debug!("Resolving {} via {}", image, url);
+        let res = self
+            .send(
+                reqwest::Method::HEAD,
+                &url,
+                MANIFEST_TYPES,
+                image,
+                &mut None,
+            )
+            .await?;
+        res.headers()
+            .get("docker-content-digest")
+            .and_then(|v| v.to_str().ok())
+            .map(str::to_owned)
+            .ok_or_else(|| {
+                BrogError::parse("registry response", format!("no digest for {}", image))
+            })
+    }
+
+    async fn version(&self, image: &ImageRef) -> Result<Option<String>, BrogError> {
+        let mut token = None;
+        let url = Self::manifest_url(image);
+        debug!("Reading the version of {} via {}", image, url);
+        let mut manifest = self
+            .get_json(&url, MANIFEST_TYPES, image, &mut token)
+            .await?;
+        // An index lists one manifest per platform.
+        if let Some(manifests) = manifest.get("manifests").and_then(|m| m.as_array()) {
+            let digest = manifests
+                .iter()
+                .find(|m| is_host_platform(m))
+                .or_else(|| manifests.first())
+                .and_then(|m| m.get("digest"))
+                .and_then(|d| d.as_str())
+                .ok_or_else(|| {
+                    BrogError::parse("registry response", format!("{} lists no manifests", url))
+                })?;
+            let url = format!("{}/manifests/{}", Self::base_url(image), digest);
+            manifest = self
+                .get_json(&url, MANIFEST_TYPES, image, &mut token)
+                .await?;
+        }
+        let config = manifest
+            .pointer("/config/digest")
+            .and_then(|d| d.as_str())
+            .ok_or_else(|| {
+                BrogError::parse("registry response", format!("no config for {}", image))
+            })?;
+        let url = format!("{}/blobs/{}", Self::base_url(image), config);
+        let config = self.get_json(&url, "*/*", image, &mut token).await?;
+        Ok(config
+            .pointer(&format!("/config/Labels/{}", VERSION_LABEL))
+            .and_then(|v| v.as_str())
+            .map(str::to_owned))
+    }
+}
+
+fn bearer(token: &str) -> Result<HeaderValue, BrogError> {
+    HeaderValue::from_str(&format!("Bearer {}", token))
+        .map_err(|e| BrogError::parse("registry token", e))
+}
+
+/// Whether the index entry `entry` is for linux on this architecture.
+fn is_host_platform(entry: &serde_json::Value) -> bool {
+    let arch = match std::env::consts::ARCH {
+        "x86_64" => "amd64",
+        "aarch64" => "arm64",
+        arch => arch,
+    };
+    let field = |name: &str| {
+        entry
+            .pointer(&format!("/platform/{}", name))
+            .and_then(|v| v.as_str())
+    };
+    field("os") == Some("linux") && field("architecture") == Some(arch)
+}
@@ -0,0 +1,111 @@
+// SPDX-License-Identifier: MIT
+// Copyright 2024 brog Authors
+
+//! Reports the outcome of each reconciliation to `REPORT_URL`.
+//!
+//! The report is POSTed as JSON and signed with the same messagesign scheme
+//! as the config fetch, except that the payload hash is the SHA-256 of the
+//! body. A report that cannot be delivered is logged and does not fail the
+//! reconciliation.
+
+use crate::{bootc::BootcHost, signer::Signer, BrogError, Outcome, ReconcileOutcome, State};
+use chrono::{DateTime, Utc};
+use reqwest::header::{HeaderValue, CONTENT_TYPE};
+use serde::Serialize;
+use std::time::Duration;
+use tracing::debug;
+
+/// What the server learns about a device after a reconciliation.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Report {
+    pub machine_id: String,
+    pub hostname: String,
+    pub booted_image: Option<String>,
+    pub booted_digest: Option<String>,
+    pub target_image: Option<String>,
+    /// The `x-clos-commit` of the config that was acted upon.
+    pub commit: Option<String>,
+    /// `success` or `failure`. A rejected target is a failure.
+    pub result: String,
+    /// The [`Outcome::name`], unset when the reconciliation failed.
+    pub outcome: Option<String>,
+    pub switch: bool,
+    pub apply: bool,
+    pub reason: Option<String>,
+    pub error: Option<String>,
+    pub duration_ms: u64,
+    pub at: DateTime<Utc>,
+    pub version: String,
+}
+
+impl Report {
+    /// Builds the report for `result`, taking the booted image from `host`.
+    pub fn new(
+        result: &Result<ReconcileOutcome, BrogError>,
+        state: &State,
+        host: Option<&BootcHost>,
+        machine_id: &str,
+        hostname: &str,
+        duration: Duration,
+    ) -> Self {
+        let booted = host.and_then(|h| h.booted_image());
+        let (outcome, error) = match result {
+            Ok(outcome) => match &outcome.outcome {
+                Outcome::Rejected { reason, .. } => (Some(outcome), Some(reason.clone())),
+                _ => (Some(outcome), None),
+            },
+            Err(e) => (None, Some(e.to_string())),
+        };
+        let (switch, apply) = outcome.map_or((false, false), |o| o.outcome.changes());
+        let result = if error.is_none() {
+            "success"
+        } else {
+            "failure"
+        };
+        Report {
+            machine_id: machine_id.trim().to_owned(),
+            hostname: hostname.trim().to_owned(),
+            booted_image: booted.map(|b| b.image.image.clone()),
+            booted_digest: booted.map(|b| b.image_digest.clone()),
+            target_image: state.target_image.clone(),
+            commit: state.commit.clone(),
+            result: result.to_owned(),
+            outcome: outcome.map(|o| o.outcome.name().to_owned()),
+            switch,
+            apply,
+            reason: outcome.map(|o| o.reason.clone()),
+            error,
+            duration_ms: duration.as_millis() as u64,
+            at: Utc::now(),
+            version: env!("CARGO_PKG_VERSION").to_owned(),
+        }
+    }
+}
+
+/// POSTs `report` to `url` with `client`, signing it when the signer has a
+/// secret.
+pub async fn send(
+    client: &reqwest::Client,
+    url: &str,
+    signer: &Signer,
+    report: &Report,
+) -> Result<(), anyhow::Error> {
+    let body = serde_json::to_vec(report)?;
+    let mut headers = if signer.is_enabled() {
+        signer.sign("POST", url, Some(&body))?
+    } else {
+        Default::default()
+    };
+    headers.insert(CONTENT_TYPE, HeaderValue::from_static("application/json"));
+    debug!("Sending report to {}", url);
+    let res = client.post(url).headers(headers).body(body).send().await?;
+    if !res.status().is_success() {
+        return Err(anyhow::anyhow!(
+            "Invalid request: {}, {}",
+            res.status(),
+            url
+        ));
+    }
+    Ok(())
+}
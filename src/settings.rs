@@ -0,0 +1,424 @@
+// SPDX-License-Identifier: MIT
+// Copyright 2024 brog Authors
+
+//! The settings of a brog agent.
+//!
+//! [`BrogConfig`] gathers everything the agent reads from its environment:
+//! the config endpoint, the service credentials, where bootc and the state
+//! live and how reconciliation behaves. It is built in code with
+//! [`BrogConfig::builder`], read from the process environment with
+//! [`BrogConfig::from_env`] or from a `KEY=value` file in the format of
+//! `/etc/brog/.config` with [`BrogConfig::from_file`].
+
+use crate::{
+    driver, error::BrogError, retry::RetryPolicy, signer::DEFAULT_REGION, splay::Splay,
+    window::MaintenanceWindow, ProcessOptions,
+};
+use std::{collections::HashMap, fmt, fs, path::Path, time::Duration};
+
+pub const DEFAULT_SERVICE_NAME: &str = "projects";
+pub const DEFAULT_BIN_PATH: &str = "/usr/bin:/bin/sbin";
+pub const DEFAULT_CONFIG_PATH: &str = "/etc/brog";
+pub const DEFAULT_POLICY_PATH: &str = "/etc/brog/policy.yaml";
+pub const DEFAULT_MACHINE_ID_PATH: &str = "/etc/machine-id";
+pub const DEFAULT_HOSTNAME_PATH: &str = "/proc/sys/kernel/hostname";
+
+#[derive(Clone)]
+pub struct BrogConfig {
+    /// The URL of brog.yaml, `ENDPOINT`.
+    pub endpoint: String,
+    pub service_key: String,
+    /// Requests are signed when set. Never printed by `Debug`.
+    pub service_secret: String,
+    pub service_name: String,
+    pub region: String,
+    /// The `PATH` bootc is looked up on.
+    pub bin_path: String,
+    /// The directory holding `state.json`.
+    pub config_path: String,
+    pub machine_id_path: String,
+    pub hostname_path: String,
+    /// The cron or English schedule `brog run` reconciles on. Not needed
+    /// for a single reconciliation.
+    pub schedule: Option<String>,
+    pub splay: Splay,
+    pub dry_run: bool,
+    pub stage_only: bool,
+    pub fetch_retry: RetryPolicy,
+    pub fetch_timeout: Duration,
+    pub bootc_timeout: Duration,
+    pub report_url: Option<String>,
+    pub signature_keys: Option<String>,
+    pub policy_path: Option<String>,
+    pub maintenance_windows: Option<Vec<MaintenanceWindow>>,
+    /// Where `brog run` serves Prometheus metrics, e.g. `127.0.0.1:9469`.
+    pub metrics_addr: Option<String>,
+}
+
+impl fmt::Debug for BrogConfig {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let secret = if self.service_secret.is_empty() {
+            ""
+        } else {
+            "<redacted>"
+        };
+        f.debug_struct("BrogConfig")
+            .field("endpoint", &self.endpoint)
+            .field("service_key", &self.service_key)
+            .field("service_secret", &secret)
+            .field("service_name", &self.service_name)
+            .field("region", &self.region)
+            .field("bin_path", &self.bin_path)
+            .field("config_path", &self.config_path)
+            .field("machine_id_path", &self.machine_id_path)
+            .field("hostname_path", &self.hostname_path)
+            .field("schedule", &self.schedule)
+            .field("splay", &self.splay)
+            .field("dry_run", &self.dry_run)
+            .field("stage_only", &self.stage_only)
+            .field("fetch_retry", &self.fetch_retry)
+            .field("fetch_timeout", &self.fetch_timeout)
+            .field("bootc_timeout", &self.bootc_timeout)
+            .field("report_url", &self.report_url)
+            .field("signature_keys", &self.signature_keys)
+            .field("policy_path", &self.policy_path)
+            .field("maintenance_windows", &self.maintenance_windows)
+            .field("metrics_addr", &self.metrics_addr)
+            .finish()
+    }
+}
+
+impl Default for BrogConfig {
+    fn default() -> Self {
+        BrogConfig {
+            endpoint: String::new(),
+            service_key: String::new(),
+            service_secret: String::new(),
+            service_name: DEFAULT_SERVICE_NAME.to_owned(),
+            region: DEFAULT_REGION.to_owned(),
+            bin_path: DEFAULT_BIN_PATH.to_owned(),
+            config_path: DEFAULT_CONFIG_PATH.to_owned(),
+            machine_id_path: DEFAULT_MACHINE_ID_PATH.to_owned(),
+            hostname_path: DEFAULT_HOSTNAME_PATH.to_owned(),
+            schedule: None,
+            splay: Splay::None,
+            dry_run: false,
+            stage_only: false,
+            fetch_retry: RetryPolicy::default(),
+            fetch_timeout: Duration::from_secs(30),
+            bootc_timeout: driver::DEFAULT_TIMEOUT,
+            report_url: None,
+            signature_keys: None,
+            policy_path: Some(DEFAULT_POLICY_PATH.to_owned()),
+            maintenance_windows: None,
+            metrics_addr: None,
+        }
+    }
+}
+
+impl BrogConfig {
+    pub fn builder(endpoint: &str) -> BrogConfigBuilder {
+        BrogConfigBuilder {
+            config: BrogConfig {
+                endpoint: endpoint.to_owned(),
+                ..Default::default()
+            },
+        }
+    }
+
+    /// Reads the settings from environment variables, see the README for
+    /// their names. `ENDPOINT` may be unset for commands that do not fetch.
+    pub fn from_env() -> Result<Self, BrogError> {
+        Self::from_lookup(|name| std::env::var(name).ok())
+    }
+
+    /// Reads the settings from a file of `KEY=value` lines using the same
+    /// names as [`BrogConfig::from_env`]. Blank lines and lines starting with
+    /// `#` are ignored, values may be quoted.
+    pub fn from_file(path: &Path) -> Result<Self, BrogError> {
+        let text = fs::read_to_string(path)
+            .map_err(|e| BrogError::config(format!("Cannot read {}: {}", path.display(), e)))?;
+        let mut vars = HashMap::new();
+        for (number, line) in text.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let line = line.strip_prefix("export ").unwrap_or(line);
+            let (name, value) = line.split_once('=').ok_or_else(|| {
+                BrogError::config(format!(
+                    "{}:{}: expected KEY=value",
+                    path.display(),
+                    number + 1
+                ))
+            })?;
+            let value = value.trim();
+            let value = ['"', '\'']
+                .iter()
+                .find_map(|q| value.strip_prefix(*q).and_then(|v| v.strip_suffix(*q)))
+                .unwrap_or(value);
+            vars.insert(name.trim().to_owned(), value.to_owned());
+        }
+        Self::from_lookup(|name| vars.get(name).cloned())
+            .map_err(|e| BrogError::config(format!("{}: {}", path.display(), e)))
+    }
+
+    fn from_lookup(get: impl Fn(&str) -> Option<String>) -> Result<Self, BrogError> {
+        let number = |name: &str| -> Result<Option<u64>, BrogError> {
+            get(name)
+                .map(|v| {
+                    v.trim().parse().map_err(|_| {
+                        BrogError::config(format!("{} must be a number, got {:?}", name, v))
+                    })
+                })
+                .transpose()
+        };
+        let flag = |name: &str| {
+            get(name).is_some_and(|v| {
+                !matches!(
+                    v.trim().to_ascii_lowercase().as_str(),
+                    "" | "0" | "false" | "no" | "n" | "off" | "f"
+                )
+            })
+        };
+        let default = BrogConfig::default();
+        let retry = RetryPolicy::default();
+        // FETCH_RETRIES counts retries, the policy counts attempts.
+        let max_attempts = match number("FETCH_RETRIES")? {
+            Some(n) => u32::try_from(n)
+                .ok()
+                .and_then(|n| n.checked_add(1))
+                .ok_or_else(|| {
+                    BrogError::config(format!(
+                        "FETCH_RETRIES must be at most {}, got {}",
+                        u32::MAX - 1,
+                        n
+                    ))
+                })?,
+            None => retry.max_attempts,
+        };
+        let config = BrogConfig {
+            endpoint: get("ENDPOINT").unwrap_or_default(),
+            service_key: get("SERVICE_KEY").unwrap_or_default(),
+            service_secret: get("SERVICE_SECRET").unwrap_or_default(),
+            service_name: get("SERVICE_NAME").unwrap_or(default.service_name),
+            region: get("SERVICE_REGION").unwrap_or(default.region),
+            bin_path: get("BIN_PATH").unwrap_or(default.bin_path),
+            config_path: get("CONFIG_PATH").unwrap_or(default.config_path),
+            machine_id_path: get("MACHINE_ID_PATH").unwrap_or(default.machine_id_path),
+            hostname_path: get("HOSTNAME_PATH").unwrap_or(default.hostname_path),
+            schedule: get("SCHEDULE"),
+            splay: get("SCHEDULE_SPLAY")
+                .map(|v| Splay::parse(&v))
+                .transpose()
+                .map_err(|e| BrogError::config(e.to_string()))?
+                .unwrap_or_default(),
+            dry_run: flag("DRY_RUN"),
+            stage_only: flag("STAGE_ONLY"),
+            fetch_retry: RetryPolicy {
+                max_attempts,
+                base_delay: number("FETCH_RETRY_BASE_MS")?
+                    .map_or(retry.base_delay, Duration::from_millis),
+                max_delay: number("FETCH_RETRY_MAX_MS")?
+                    .map_or(retry.max_delay, Duration::from_millis),
+            },
+            fetch_timeout: number("FETCH_TIMEOUT")?
+                .map_or(default.fetch_timeout, Duration::from_secs),
+            bootc_timeout: number("BOOTC_TIMEOUT")?
+                .map_or(default.bootc_timeout, Duration::from_secs),
+            report_url: get("REPORT_URL").filter(|url| !url.is_empty()),
+            signature_keys: get("SIGNATURE_KEYS").filter(|keys| !keys.is_empty()),
+            policy_path: Some(
+                get("IMAGE_POLICY").unwrap_or_else(|| DEFAULT_POLICY_PATH.to_owned()),
+            ),
+            maintenance_windows: get("MAINTENANCE_WINDOWS")
+                .map(|v| MaintenanceWindow::parse_list(&v))
+                .transpose()
+                .map_err(|e| BrogError::config(e.to_string()))?,
+            metrics_addr: get("METRICS_ADDR").filter(|addr| !addr.is_empty()),
+        };
+        config.validate()?;
+        Ok(config)
+    }
+
+    /// Checks that the settings are usable, naming the first one that is
+    /// not. An empty endpoint is allowed here and rejected when fetching.
+    pub fn validate(&self) -> Result<(), BrogError> {
+        if !self.endpoint.is_empty() {
+            url::Url::parse(&self.endpoint).map_err(|e| {
+                BrogError::config(format!("ENDPOINT {:?} is not a URL: {}", self.endpoint, e))
+            })?;
+        }
+        if let Some(url) = &self.report_url {
+            url::Url::parse(url).map_err(|e| {
+                BrogError::config(format!("REPORT_URL {:?} is not a URL: {}", url, e))
+            })?;
+        }
+        if let Some(addr) = &self.metrics_addr {
+            addr.parse::<std::net::SocketAddr>().map_err(|e| {
+                BrogError::config(format!(
+                    "METRICS_ADDR {:?} is not an address and port: {}",
+                    addr, e
+                ))
+            })?;
+        }
+        if !self.service_secret.is_empty() && self.service_key.is_empty() {
+            return Err(BrogError::config(
+                "SERVICE_SECRET is set without SERVICE_KEY",
+            ));
+        }
+        if self.service_name.is_empty() {
+            return Err(BrogError::config("SERVICE_NAME cannot be empty"));
+        }
+        if self.config_path.is_empty() {
+            return Err(BrogError::config("CONFIG_PATH cannot be empty"));
+        }
+        if self.fetch_retry.max_attempts == 0 {
+            return Err(BrogError::config(
+                "fetch retry policy needs at least one attempt",
+            ));
+        }
+        if self.fetch_timeout.is_zero() {
+            return Err(BrogError::config("FETCH_TIMEOUT must be greater than 0"));
+        }
+        if self.bootc_timeout.is_zero() {
+            return Err(BrogError::config("BOOTC_TIMEOUT must be greater than 0"));
+        }
+        Ok(())
+    }
+
+    /// The [`ProcessOptions`] these settings describe.
+    pub(crate) fn options(&self) -> ProcessOptions {
+        ProcessOptions {
+            dry_run: self.dry_run,
+            stage_only: self.stage_only,
+            fetch_retry: self.fetch_retry.clone(),
+            fetch_timeout: self.fetch_timeout,
+            region: self.region.clone(),
+            report_url: self.report_url.clone(),
+            signature_keys: self.signature_keys.clone(),
+            policy_path: self.policy_path.clone(),
+            registry: None,
+            driver: None,
+            bootc_timeout: self.bootc_timeout,
+            maintenance_windows: self.maintenance_windows.clone(),
+            machine_id_path: self.machine_id_path.clone(),
+            hostname_path: self.hostname_path.clone(),
+            metrics: None,
+        }
+    }
+}
+
+/// Builds a [`BrogConfig`], validating it in [`BrogConfigBuilder::build`].
+#[derive(Debug, Clone)]
+pub struct BrogConfigBuilder {
+    config: BrogConfig,
+}
+
+impl BrogConfigBuilder {
+    pub fn service_key(mut self, key: &str) -> Self {
+        self.config.service_key = key.to_owned();
+        self
+    }
+
+    pub fn service_secret(mut self, secret: &str) -> Self {
+        self.config.service_secret = secret.to_owned();
+        self
+    }
+
+    pub fn service_name(mut self, name: &str) -> Self {
+        self.config.service_name = name.to_owned();
+        self
+    }
+
+    pub fn region(mut self, region: &str) -> Self {
+        self.config.region = region.to_owned();
+        self
+    }
+
+    pub fn bin_path(mut self, bin_path: &str) -> Self {
+        self.config.bin_path = bin_path.to_owned();
+        self
+    }
+
+    pub fn config_path(mut self, config_path: &str) -> Self {
+        self.config.config_path = config_path.to_owned();
+        self
+    }
+
+    pub fn machine_id_path(mut self, path: &str) -> Self {
+        self.config.machine_id_path = path.to_owned();
+        self
+    }
+
+    pub fn hostname_path(mut self, path: &str) -> Self {
+        self.config.hostname_path = path.to_owned();
+        self
+    }
+
+    pub fn schedule(mut self, schedule: &str) -> Self {
+        self.config.schedule = Some(schedule.to_owned());
+        self
+    }
+
+    pub fn splay(mut self, splay: Splay) -> Self {
+        self.config.splay = splay;
+        self
+    }
+
+    pub fn dry_run(mut self, dry_run: bool) -> Self {
+        self.config.dry_run = dry_run;
+        self
+    }
+
+    pub fn stage_only(mut self, stage_only: bool) -> Self {
+        self.config.stage_only = stage_only;
+        self
+    }
+
+    pub fn fetch_retry(mut self, policy: RetryPolicy) -> Self {
+        self.config.fetch_retry = policy;
+        self
+    }
+
+    pub fn fetch_timeout(mut self, timeout: Duration) -> Self {
+        self.config.fetch_timeout = timeout;
+        self
+    }
+
+    pub fn bootc_timeout(mut self, timeout: Duration) -> Self {
+        self.config.bootc_timeout = timeout;
+        self
+    }
+
+    pub fn report_url(mut self, url: &str) -> Self {
+        self.config.report_url = Some(url.to_owned());
+        self
+    }
+
+    pub fn signature_keys(mut self, dir: &str) -> Self {
+        self.config.signature_keys = Some(dir.to_owned());
+        self
+    }
+
+    /// The image policy file, `None` to allow any image.
+    pub fn policy_path(mut self, path: Option<&str>) -> Self {
+        self.config.policy_path = path.map(str::to_owned);
+        self
+    }
+
+    pub fn maintenance_windows(mut self, windows: Vec<MaintenanceWindow>) -> Self {
+        self.config.maintenance_windows = Some(windows);
+        self
+    }
+
+    pub fn metrics_addr(mut self, addr: &str) -> Self {
+        self.config.metrics_addr = Some(addr.to_owned());
+        self
+    }
+
+    pub fn build(self) -> Result<BrogConfig, BrogError> {
+        self.config.validate()?;
+        Ok(self.config)
+    }
+}
@@ -0,0 +1,138 @@
+// SPDX-License-Identifier: MIT
+// Copyright 2024 brog Authors
+
+//! messagesign request signing for any method and body.
+//!
+//! Every signed request carries the device identity, a nonce and the hex
+//! SHA-256 of its body in `x-mhl-content-sha256`. Requests without a body,
+//! such as the config fetch, use [`UNSIGNED_PAYLOAD`] instead.
+
+use crate::error::BrogError;
+use messagesign::signature;
+use rand::Rng;
+use reqwest::header::{HeaderMap, HeaderName, HeaderValue, AUTHORIZATION};
+use sha2::{Digest, Sha256};
+use std::fmt;
+use tracing::debug;
+
+pub const UNSIGNED_PAYLOAD: &str = "UNSIGNED-PAYLOAD";
+
+/// The region used when `SERVICE_REGION` is not set.
+pub const DEFAULT_REGION: &str = "global";
+
+/// Signs requests on behalf of one device.
+#[derive(Clone)]
+pub struct Signer {
+    key: String,
+    secret: String,
+    service: String,
+    region: String,
+    machine_id: String,
+    hostname: String,
+}
+
+impl fmt::Debug for Signer {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Signer")
+            .field("key", &self.key)
+            .field("service", &self.service)
+            .field("region", &self.region)
+            .field("machine_id", &self.machine_id)
+            .field("hostname", &self.hostname)
+            .finish_non_exhaustive()
+    }
+}
+
+impl Signer {
+    pub fn new(key: &str, secret: &str, service: &str) -> Self {
+        Signer {
+            key: key.to_owned(),
+            secret: secret.to_owned(),
+            service: service.to_owned(),
+            region: DEFAULT_REGION.to_owned(),
+            machine_id: String::new(),
+            hostname: String::new(),
+        }
+    }
+
+    pub fn with_region(mut self, region: &str) -> Self {
+        self.region = region.to_owned();
+        self
+    }
+
+    /// Sets the machine-id and hostname sent with every request. They are
+    /// signed as read from disk and trimmed in the headers.
+    pub fn with_identity(mut self, machine_id: &str, hostname: &str) -> Self {
+        self.machine_id = machine_id.to_owned();
+        self.hostname = hostname.to_owned();
+        self
+    }
+
+    /// False when no secret is configured and requests are sent unsigned.
+    pub fn is_enabled(&self) -> bool {
+        !self.secret.is_empty()
+    }
+
+    pub fn region(&self) -> &str {
+        &self.region
+    }
+
+    /// The hex SHA-256 of a request body.
+    pub fn payload_hash(body: &[u8]) -> String {
+        hex::encode(Sha256::digest(body))
+    }
+
+    /// The headers that authenticate a `method` request to `url`. A `None`
+    /// body is signed as [`UNSIGNED_PAYLOAD`]. Each call uses a new nonce.
+    pub fn sign(
+        &self,
+        method: &str,
+        url: &str,
+        body: Option<&[u8]>,
+    ) -> Result<HeaderMap, anyhow::Error> {
+        let payload_hash = body.map_or_else(|| UNSIGNED_PAYLOAD.to_owned(), Self::payload_hash);
+        let url = url::Url::parse(url)?;
+        let nonce = rand::rng().random::<u32>().to_string();
+        debug!(
+            "Signing service: method:{} payload_hash:{} region:{} nonce:{}",
+            method, payload_hash, self.region, nonce
+        );
+        let sig = signature(
+            &url,
+            method,
+            &self.key,
+            &self.secret,
+            &self.region,
+            &self.service,
+            &self.machine_id,
+            &self.hostname,
+            &payload_hash,
+            &nonce,
+        )
+        .map_err(|e| BrogError::auth(format!("Signature Creation Failure {}", e)))?;
+
+        let mut headers = HeaderMap::new();
+        headers.insert(
+            HeaderName::from_static("x-mhl-content-sha256"),
+            HeaderValue::from_str(&payload_hash)?,
+        );
+        headers.insert(
+            HeaderName::from_static("x-mhl-date"),
+            HeaderValue::from_str(&sig.date_time)?,
+        );
+        headers.insert(AUTHORIZATION, HeaderValue::from_str(&sig.auth_header)?);
+        headers.insert(
+            HeaderName::from_static("x-mhl-mid"),
+            HeaderValue::from_str(self.machine_id.trim())?,
+        );
+        headers.insert(
+            HeaderName::from_static("x-mhl-hostname"),
+            HeaderValue::from_str(self.hostname.trim())?,
+        );
+        headers.insert(
+            HeaderName::from_static("x-mhl-nonce"),
+            HeaderValue::from_str(&nonce)?,
+        );
+        Ok(headers)
+    }
+}
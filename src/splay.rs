@@ -0,0 +1,68 @@
+// SPDX-License-Identifier: MIT
+// Copyright 2024 brog Authors
+
+//! Spreads scheduled fetches of a fleet across a window.
+//!
+//! `SCHEDULE_SPLAY` delays every scheduled fetch by an offset up to the
+//! given number of seconds. By default the offset is derived from the
+//! machine-id, so a device always fetches at the same point in the window.
+//! Prefixing the value with `random:` picks a new offset on every tick.
+
+use rand::Rng;
+use sha2::{Digest, Sha256};
+use std::time::Duration;
+
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum Splay {
+    #[default]
+    None,
+    /// A stable offset derived from the machine-id.
+    Machine(Duration),
+    /// A new random offset for every tick.
+    Random(Duration),
+}
+
+impl Splay {
+    /// Parses `<seconds>`, `machine:<seconds>` or `random:<seconds>`.
+    pub fn parse(value: &str) -> Result<Self, anyhow::Error> {
+        let value = value.trim();
+        let (mode, seconds) = value.split_once(':').unwrap_or(("machine", value));
+        let window = seconds
+            .trim()
+            .parse::<u64>()
+            .map(Duration::from_secs)
+            .map_err(|e| anyhow::anyhow!("Invalid SCHEDULE_SPLAY {}: {}", value, e))?;
+        if window.is_zero() {
+            return Ok(Splay::None);
+        }
+        match mode.trim() {
+            "machine" => Ok(Splay::Machine(window)),
+            "random" => Ok(Splay::Random(window)),
+            other => Err(anyhow::anyhow!(
+                "Invalid SCHEDULE_SPLAY {}: unknown mode {}",
+                value,
+                other
+            )),
+        }
+    }
+
+    /// The delay before the fetch of this tick, always below the window.
+    pub fn delay(&self, machineid: &str) -> Duration {
+        match *self {
+            Splay::None => Duration::ZERO,
+            Splay::Machine(window) => {
+                // Salted so the offset is independent of the canary bucket.
+                let mut hasher = Sha256::new();
+                hasher.update(b"splay:");
+                hasher.update(machineid.trim().as_bytes());
+                let digest = hasher.finalize();
+                let mut bytes = [0u8; 8];
+                bytes.copy_from_slice(&digest[..8]);
+                Duration::from_millis(u64::from_be_bytes(bytes) % window.as_millis() as u64)
+            }
+            Splay::Random(window) => {
+                Duration::from_millis(rand::rng().random_range(0..window.as_millis() as u64))
+            }
+        }
+    }
+}
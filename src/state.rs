@@ -0,0 +1,209 @@
+// SPDX-License-Identifier: MIT
+// Copyright 2024 brog Authors
+
+//! Persistent agent state kept in `<CONFIG_PATH>/state.json`.
+//!
+//! The file is always replaced atomically: the new contents are written to a
+//! temporary file in the same directory, synced, and renamed over the old
+//! file, so a power loss leaves either the old or the new state on disk.
+
+use crate::{canary::Rollout, health::PendingCheck};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use std::{
+    fs,
+    io::Write,
+    path::{Path, PathBuf},
+    sync::atomic::{AtomicU64, Ordering},
+};
+use tracing::{debug, warn};
+
+pub const STATE_FILE: &str = "state.json";
+
+/// Numbers the temporary files of this process.
+static TMP_COUNTER: AtomicU64 = AtomicU64::new(0);
+
+/// The number of [`HistoryEntry`] values kept in the state file.
+pub const MAX_HISTORY: usize = 50;
+
+/// Everything brog remembers between ticks and across reboots.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default, rename_all = "camelCase")]
+pub struct State {
+    /// The `x-clos-commit` of the last fetched config.
+    pub commit: Option<String>,
+    pub last_fetch: Option<DateTime<Utc>>,
+    /// Attempts used by the last config fetch.
+    pub fetch_attempts: u32,
+    pub config_cache: Option<ConfigCache>,
+    /// The image named by the last fetched config.
+    pub target_image: Option<String>,
+    pub applied_image: Option<String>,
+    pub applied_digest: Option<String>,
+    pub last_success: Option<DateTime<Utc>>,
+    pub last_failure: Option<DateTime<Utc>>,
+    pub last_error: Option<String>,
+    /// Consecutive failed `bootc switch` attempts.
+    pub attempts: u32,
+    pub rollout: Option<Rollout>,
+    /// The percentage of devices covered by the current canary stage.
+    pub canary_percent: Option<u8>,
+    pub pending_check: Option<PendingCheck>,
+    pub rollbacks: Vec<RollbackRecord>,
+    /// The most recent events, oldest first.
+    pub history: Vec<HistoryEntry>,
+}
+
+/// The last config fetched from `ENDPOINT` with the validators needed for a
+/// conditional request. The body is reused when the server answers
+/// `304 Not Modified`.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ConfigCache {
+    pub url: String,
+    pub etag: Option<String>,
+    pub last_modified: Option<String>,
+    pub body: String,
+    /// The detached signature the body was verified with, if any.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub signature: Option<String>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct RollbackRecord {
+    pub image: String,
+    /// The digest that failed. Records written by older versions have none
+    /// and cover every digest of `image`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub digest: Option<String>,
+    pub reason: String,
+    pub at: DateTime<Utc>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct HistoryEntry {
+    pub at: DateTime<Utc>,
+    pub event: String,
+    pub image: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub detail: Option<String>,
+}
+
+impl State {
+    /// Appends an event to the history, dropping the oldest entries beyond
+    /// [`MAX_HISTORY`].
+    pub fn record(&mut self, event: &str, image: &str, detail: Option<String>) {
+        self.history.push(HistoryEntry {
+            at: Utc::now(),
+            event: event.to_owned(),
+            image: image.to_owned(),
+            detail,
+        });
+        if self.history.len() > MAX_HISTORY {
+            let excess = self.history.len() - MAX_HISTORY;
+            self.history.drain(..excess);
+        }
+    }
+
+    /// True when `image` at `digest` was rolled back and must not be
+    /// switched to again. An unknown `digest` matches any rolled back digest
+    /// of `image`, as the tag may still point at it.
+    pub fn is_rolled_back(&self, image: &str, digest: Option<&str>) -> bool {
+        self.rollbacks.iter().any(|r| {
+            r.image == image
+                && match (r.digest.as_deref(), digest) {
+                    (Some(failed), Some(digest)) => failed == digest,
+                    _ => true,
+                }
+        })
+    }
+}
+
+pub struct StateStore {
+    path: PathBuf,
+    read_only: bool,
+}
+
+impl StateStore {
+    pub fn new(service_location: &str) -> Self {
+        StateStore {
+            path: Path::new(service_location).join(STATE_FILE),
+            read_only: false,
+        }
+    }
+
+    /// A store that loads normally but never writes, used for dry runs.
+    pub fn read_only(mut self) -> Self {
+        self.read_only = true;
+        self
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Loads the state, importing the commit from a legacy `sha` file when
+    /// no state has been written yet.
+    pub fn load(&self) -> Result<State, anyhow::Error> {
+        if self.path.exists() {
+            let text = fs::read_to_string(&self.path)?;
+            return serde_json::from_str(&text)
+                .map_err(|e| anyhow::anyhow!("Invalid state file {}: {}", self.path.display(), e));
+        }
+        let mut state = State::default();
+        let shapath = self.path.with_file_name("sha");
+        if shapath.exists() {
+            let commit = fs::read_to_string(&shapath)?.trim().to_owned();
+            if !commit.is_empty() {
+                debug!("Importing commit {} from {}", commit, shapath.display());
+                state.commit = Some(commit);
+            }
+        }
+        Ok(state)
+    }
+
+    pub fn save(&self, state: &State) -> Result<(), anyhow::Error> {
+        if self.read_only {
+            debug!("Not writing read only state: {}", self.path.display());
+            return Ok(());
+        }
+        debug!("Writing state: {}", self.path.display());
+        // Every write gets its own temporary file, so concurrent writers
+        // never write into each other's file.
+        let tmppath = self.path.with_extension(format!(
+            "json.{}.{}.tmp",
+            std::process::id(),
+            TMP_COUNTER.fetch_add(1, Ordering::Relaxed)
+        ));
+        let written = fs::File::create(&tmppath).and_then(|mut f| {
+            f.write_all(serde_json::to_string_pretty(state)?.as_bytes())?;
+            f.sync_all()?;
+            fs::rename(&tmppath, &self.path)
+        });
+        if let Err(e) = written {
+            let _ = fs::remove_file(&tmppath);
+            return Err(e.into());
+        }
+        // Persist the rename itself. Not every filesystem supports syncing a
+        // directory, so this is best effort.
+        if let Some(dir) = self.path.parent() {
+            if let Err(e) = fs::File::open(dir).and_then(|d| d.sync_all()) {
+                warn!("Could not sync {}: {}", dir.display(), e);
+            }
+        }
+        Ok(())
+    }
+
+    /// Loads the state, applies `f` and saves the result.
+    pub fn update<F>(&self, f: F) -> Result<State, anyhow::Error>
+    where
+        F: FnOnce(&mut State),
+    {
+        let mut state = self.load()?;
+        f(&mut state);
+        self.save(&state)?;
+        Ok(state)
+    }
+}
@@ -0,0 +1,106 @@
+// SPDX-License-Identifier: MIT
+// Copyright 2024 brog Authors
+
+//! Detached Ed25519 signatures on the brog.yaml served from `ENDPOINT`.
+//!
+//! When `SIGNATURE_KEYS` names a directory of public keys every config must
+//! be signed by one of them before brog acts on it. The signature is the
+//! base64 encoded Ed25519 signature of the exact response body, taken from
+//! the `x-brog-signature` response header or, when that is missing, from a
+//! sibling URL with `.sig` appended to the path.
+//!
+//! A key file ends in `.pub` and holds the base64 encoded 32 byte public key.
+//! Blank lines and lines starting with `#` are ignored.
+
+use crate::{error::BrogError, signer::Signer};
+use base64::{engine::general_purpose::STANDARD, Engine};
+use ed25519_dalek::{Signature, Verifier, VerifyingKey};
+use reqwest::header::HeaderName;
+use std::{fs, path::Path};
+use tracing::debug;
+
+pub const SIGNATURE_HEADER: HeaderName = HeaderName::from_static("x-brog-signature");
+
+/// The trusted public keys.
+#[derive(Debug, Clone)]
+pub struct Keyring {
+    keys: Vec<(String, VerifyingKey)>,
+}
+
+impl Keyring {
+    /// Loads every `*.pub` file in `dir`. An empty keyring is an error so
+    /// that a missing key never disables verification.
+    pub fn load(dir: &Path) -> Result<Self, anyhow::Error> {
+        let mut keys = vec![];
+        let entries = fs::read_dir(dir)
+            .map_err(|e| anyhow::anyhow!("Cannot read keys from {}: {}", dir.display(), e))?;
+        for entry in entries {
+            let path = entry?.path();
+            if path.extension().is_none_or(|ext| ext != "pub") {
+                continue;
+            }
+            let key = parse_public_key(&fs::read_to_string(&path)?)
+                .map_err(|e| anyhow::anyhow!("Invalid key {}: {}", path.display(), e))?;
+            keys.push((path.display().to_string(), key));
+        }
+        if keys.is_empty() {
+            return Err(anyhow::anyhow!("No *.pub keys found in {}", dir.display()));
+        }
+        keys.sort_by(|a, b| a.0.cmp(&b.0));
+        Ok(Keyring { keys })
+    }
+
+    /// Checks `signature` against `body`, returning the name of the key that
+    /// signed it.
+    pub fn verify(&self, body: &[u8], signature: Option<&str>) -> Result<&str, anyhow::Error> {
+        let signature = signature.ok_or_else(|| anyhow::anyhow!("config is not signed"))?;
+        let bytes = STANDARD
+            .decode(signature.trim())
+            .map_err(|e| anyhow::anyhow!("invalid signature encoding: {}", e))?;
+        let signature = Signature::from_slice(&bytes)
+            .map_err(|e| anyhow::anyhow!("invalid signature: {}", e))?;
+        self.keys
+            .iter()
+            .find(|(_, key)| key.verify(body, &signature).is_ok())
+            .map(|(name, _)| name.as_str())
+            .ok_or_else(|| anyhow::anyhow!("signature does not match any trusted key"))
+    }
+}
+
+pub fn parse_public_key(text: &str) -> Result<VerifyingKey, anyhow::Error> {
+    let encoded: String = text
+        .lines()
+        .map(str::trim)
+        .filter(|l| !l.is_empty() && !l.starts_with('#'))
+        .collect();
+    let bytes: [u8; 32] = STANDARD
+        .decode(encoded)?
+        .try_into()
+        .map_err(|_| anyhow::anyhow!("expected a 32 byte Ed25519 public key"))?;
+    Ok(VerifyingKey::from_bytes(&bytes)?)
+}
+
+/// Fetches the signature published next to `ep`, or `None` when there is
+/// none.
+pub async fn fetch_signature(
+    client: &reqwest::Client,
+    ep: &str,
+    signer: &Signer,
+) -> Result<Option<String>, anyhow::Error> {
+    let mut url = url::Url::parse(ep)?;
+    url.set_path(&format!("{}.sig", url.path()));
+    debug!("Fetching config signature from {}", url);
+    let headers = if signer.is_enabled() {
+        signer.sign("GET", url.as_str(), None)?
+    } else {
+        Default::default()
+    };
+    let res = client.get(url.clone()).headers(headers).send().await?;
+    if res.status() == reqwest::StatusCode::NOT_FOUND {
+        return Ok(None);
+    }
+    if !res.status().is_success() {
+        return Err(BrogError::http_status(url.as_str(), res.status()).into());
+    }
+    Ok(Some(res.text().await?.trim().to_owned()))
+}
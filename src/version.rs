@@ -0,0 +1,52 @@
+// SPDX-License-Identifier: MIT
+// Copyright 2024 brog Authors
+
+//! Ordering of image versions such as `41.20241215.0` or `v1.2.3-rc1`.
+//!
+//! Versions are split on `.`. Numeric parts compare as numbers, anything
+//! else as text, and a version with more parts is newer than its prefix. As
+//! in semver, a `-` suffix marks a pre-release that is older than its
+//! release, so `1.2.3-rc1` comes before `1.2.3`, and `+` build metadata is
+//! ignored. A leading `v` is ignored.
+
+use std::cmp::Ordering;
+
+pub fn compare(a: &str, b: &str) -> Ordering {
+    let ((a, a_pre), (b, b_pre)) = (split(a), split(b));
+    compare_parts(a, b).then_with(|| match (a_pre, b_pre) {
+        (None, None) => Ordering::Equal,
+        (Some(_), None) => Ordering::Less,
+        (None, Some(_)) => Ordering::Greater,
+        (Some(a), Some(b)) => compare_parts(a, b),
+    })
+}
+
+/// The release and pre-release parts of `version`.
+fn split(version: &str) -> (&str, Option<&str>) {
+    let version = version.trim().trim_start_matches('v');
+    let version = version.split_once('+').map_or(version, |(v, _)| v);
+    match version.split_once('-') {
+        Some((release, pre)) => (release, Some(pre)),
+        None => (version, None),
+    }
+}
+
+fn compare_parts(a: &str, b: &str) -> Ordering {
+    let (a, b): (Vec<&str>, Vec<&str>) =
+        (a.split(['.', '-']).collect(), b.split(['.', '-']).collect());
+    for (x, y) in a.iter().zip(&b) {
+        let ordering = match (x.parse::<u64>(), y.parse::<u64>()) {
+            (Ok(x), Ok(y)) => x.cmp(&y),
+            _ => x.cmp(y),
+        };
+        if ordering != Ordering::Equal {
+            return ordering;
+        }
+    }
+    a.len().cmp(&b.len())
+}
+
+/// True when moving from `booted` to `target` goes backwards.
+pub fn is_downgrade(booted: &str, target: &str) -> bool {
+    compare(target, booted) == Ordering::Less
+}
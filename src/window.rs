@@ -0,0 +1,178 @@
+// SPDX-License-Identifier: MIT
+// Copyright 2024 brog Authors
+
+//! Maintenance windows that gate the reboot into a new image.
+//!
+//! Outside every window brog only stages an update with `bootc switch`.
+//! Inside a window it reboots into the staged deployment. Fetching is not
+//! affected and stays on `SCHEDULE`.
+//!
+//! Windows come from `maintenanceWindows` in brog.yaml or, overriding
+//! those, from the `MAINTENANCE_WINDOWS` setting on the device, written as
+//! `Mon-Fri 02:00-04:00 Europe/Berlin; Sat,Sun 00:00-06:00`.
+
+use chrono::{DateTime, Datelike, NaiveTime, Utc, Weekday};
+use chrono_tz::Tz;
+use serde::{Deserialize, Serialize};
+use std::str::FromStr;
+
+const TIME_FORMAT: &str = "%H:%M";
+
+/// A recurring period in which an update may reboot the device.
+///
+/// A window whose `end` is before its `start` runs past midnight into the
+/// next day. `days` names the day the window starts on and is every day
+/// when empty.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct MaintenanceWindow {
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub days: Vec<Weekday>,
+    #[serde(with = "time_of_day")]
+    pub start: NaiveTime,
+    #[serde(with = "time_of_day")]
+    pub end: NaiveTime,
+    /// An IANA timezone such as `Europe/Berlin`. Defaults to UTC.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub timezone: Option<String>,
+}
+
+impl MaintenanceWindow {
+    pub(crate) fn validate(&self, path: &str) -> Result<(), anyhow::Error> {
+        self.tz()
+            .map_err(|e| anyhow::anyhow!("Invalid brog.yaml: {}.timezone: {}", path, e))?;
+        Ok(())
+    }
+
+    fn tz(&self) -> Result<Tz, anyhow::Error> {
+        match &self.timezone {
+            Some(name) => {
+                Tz::from_str(name).map_err(|_| anyhow::anyhow!("unknown timezone {:?}", name))
+            }
+            None => Ok(Tz::UTC),
+        }
+    }
+
+    fn on(&self, day: Weekday) -> bool {
+        self.days.is_empty() || self.days.contains(&day)
+    }
+
+    /// True when `now` falls inside this window.
+    pub fn contains(&self, now: DateTime<Utc>) -> bool {
+        let tz = match self.tz() {
+            Ok(tz) => tz,
+            Err(_) => return false,
+        };
+        let local = now.with_timezone(&tz);
+        let time = local.time();
+        let day = local.weekday();
+        if self.start < self.end {
+            self.on(day) && self.start <= time && time < self.end
+        } else {
+            // Runs past midnight, or all day when start equals end.
+            (self.on(day) && time >= self.start) || (self.on(day.pred()) && time < self.end)
+        }
+    }
+
+    /// Parses one window of a `MAINTENANCE_WINDOWS` value, e.g.
+    /// `Mon-Fri 02:00-04:00 Europe/Berlin`.
+    pub fn parse(value: &str) -> Result<Self, anyhow::Error> {
+        let invalid =
+            |reason: String| anyhow::anyhow!("Invalid maintenance window {:?}: {}", value, reason);
+        let mut fields: Vec<&str> = value.split_whitespace().collect();
+        let days = match fields.first() {
+            Some(field) if !field.contains(':') => {
+                let days = parse_days(field).map_err(invalid)?;
+                fields.remove(0);
+                days
+            }
+            _ => vec![],
+        };
+        let (start, end) = match fields.first().and_then(|range| range.split_once('-')) {
+            Some((start, end)) => (
+                NaiveTime::parse_from_str(start, TIME_FORMAT)
+                    .map_err(|e| invalid(format!("{}: {}", start, e)))?,
+                NaiveTime::parse_from_str(end, TIME_FORMAT)
+                    .map_err(|e| invalid(format!("{}: {}", end, e)))?,
+            ),
+            None => {
+                return Err(invalid(
+                    "expected a time range such as 02:00-04:00".to_owned(),
+                ))
+            }
+        };
+        let timezone = fields.get(1).map(|tz| tz.to_string());
+        if fields.len() > 2 {
+            return Err(invalid(format!("unexpected {:?}", fields[2])));
+        }
+        let window = MaintenanceWindow {
+            days,
+            start,
+            end,
+            timezone,
+        };
+        window.tz().map_err(|e| invalid(e.to_string()))?;
+        Ok(window)
+    }
+
+    /// Parses a `;` separated list of windows.
+    pub fn parse_list(value: &str) -> Result<Vec<Self>, anyhow::Error> {
+        value
+            .split(';')
+            .map(str::trim)
+            .filter(|w| !w.is_empty())
+            .map(MaintenanceWindow::parse)
+            .collect()
+    }
+}
+
+/// True when no windows are configured or `now` falls inside one of them.
+pub fn is_open(windows: &[MaintenanceWindow], now: DateTime<Utc>) -> bool {
+    windows.is_empty() || windows.iter().any(|w| w.contains(now))
+}
+
+/// Parses `Mon-Fri`, `Sat,Sun` or `*`.
+fn parse_days(value: &str) -> Result<Vec<Weekday>, String> {
+    if value == "*" {
+        return Ok(vec![]);
+    }
+    let day =
+        |name: &str| Weekday::from_str(name).map_err(|_| format!("{:?} is not a weekday", name));
+    let mut days = vec![];
+    for part in value.split(',') {
+        match part.split_once('-') {
+            Some((first, last)) => {
+                let (mut current, last) = (day(first)?, day(last)?);
+                days.push(current);
+                while current != last {
+                    current = current.succ();
+                    days.push(current);
+                }
+            }
+            None => days.push(day(part)?),
+        }
+    }
+    Ok(days)
+}
+
+mod time_of_day {
+    use super::TIME_FORMAT;
+    use chrono::NaiveTime;
+    use serde::{de, Deserialize, Deserializer, Serializer};
+
+    pub fn serialize<S>(time: &NaiveTime, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        serializer.serialize_str(&time.format(TIME_FORMAT).to_string())
+    }
+
+    pub fn deserialize<'de, D>(deserializer: D) -> Result<NaiveTime, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        let text = String::deserialize(deserializer)?;
+        NaiveTime::parse_from_str(&text, TIME_FORMAT)
+            .map_err(|e| de::Error::custom(format!("{:?} is not a HH:MM time: {}", text, e)))
+    }
+}
@@ -1,6 +1,34 @@
 use std::env;
 
-use brog::{process, run_command_text};
+// The positional entry point is deprecated but still tested.
+#[allow(deprecated)]
+use brog::process;
+use brog::{
+    run_command_text, settings::BrogConfigBuilder, Agent, BrogConfig, BrogError, ReconcileOutcome,
+};
+
+/// A fresh state directory, removed when the returned guard is dropped.
+fn state_dir() -> (tempfile::TempDir, String) {
+    let tmp = tempfile::tempdir().unwrap();
+    let dir = tmp.path().to_string_lossy().into_owned();
+    (tmp, dir)
+}
+
+/// A config that keeps its state in `dir` and reads the device identity from
+/// there rather than from /etc.
+fn test_config(endpoint: &str, bin_path: &str, dir: &str) -> BrogConfigBuilder {
+    std::fs::write(format!("{}/machine-id", dir), "0123456789abcdef\n").unwrap();
+    std::fs::write(format!("{}/hostname", dir), "brog-test\n").unwrap();
+    BrogConfig::builder(endpoint)
+        .bin_path(bin_path)
+        .config_path(dir)
+        .machine_id_path(&format!("{}/machine-id", dir))
+        .hostname_path(&format!("{}/hostname", dir))
+}
+
+async fn reconcile(config: BrogConfigBuilder) -> Result<ReconcileOutcome, BrogError> {
+    Agent::new(config.build()?)?.reconcile().await
+}
 
 #[tokio::test]
 async fn test_bootc_output() {
@@ -40,6 +68,39 @@ async fn test_bootc_err_output_fail() {
 }
 
 #[tokio::test]
+async fn test_bootc_stderr_warning_ok() {
+    use brog::{run_command, CommandError};
+    let path = env::current_dir().unwrap_or_default().join("mocks/warning");
+    let output = run_command(
+        &["switch", "quay.io/fedora/fedora-bootc:41"],
+        path.to_str().unwrap(),
+    )
+    .unwrap();
+    assert!(output.status.success());
+    assert_eq!(
+        output.stdout.trim(),
+        "bootc switch quay.io/fedora/fedora-bootc:41"
+    );
+    assert!(output.stderr.contains("warning"));
+    let text = run_command_text(vec!["status"], path.to_str().unwrap()).unwrap();
+    assert_eq!(text.trim(), "bootc status");
+
+    let path = env::current_dir().unwrap_or_default().join("mocks/error");
+    let err = run_command(&["upgrade", "--apply"], path.to_str().unwrap()).unwrap_err();
+    match &err {
+        CommandError::Failed { status, stderr, .. } => {
+            assert_eq!(status.code(), Some(1));
+            assert_eq!(stderr.trim(), "upgrade --apply");
+        }
+        e => panic!("unexpected error {:?}", e),
+    }
+    assert!(err.to_string().contains("upgrade --apply"));
+    let err = run_command(&[], "/nonexistent").unwrap_err();
+    assert!(matches!(err, CommandError::Spawn { .. }));
+}
+
+#[tokio::test]
+#[allow(deprecated)]
 async fn test_process_no_endpoint() {
     use wiremock::matchers::method;
     use wiremock::matchers::path;
@@ -52,19 +113,37 @@ async fn test_process_no_endpoint() {
         .mount(&mock_server)
         .await;
 
+    let (_tmp, dir) = state_dir();
     let result = process(
         "".to_string(),
         "".to_string(),
         "".to_string(),
         "".to_string(),
         "brog".to_string(),
+        dir,
+    )
+    .await;
+    assert!(result.is_err());
+
+    // An empty state directory is refused rather than writing ./state.json.
+    let result = process(
+        mock_server.uri(),
+        "".to_string(),
+        "".to_string(),
+        "".to_string(),
+        "brog".to_string(),
         "".to_string(),
     )
     .await;
-    assert!(result.is_err())
+    assert!(
+        matches!(result, Err(BrogError::Config { .. })),
+        "{:?}",
+        result
+    );
 }
 
 #[tokio::test]
+#[allow(deprecated)]
 async fn test_process_404() {
     use wiremock::matchers::method;
     use wiremock::matchers::path;
@@ -77,19 +156,21 @@ async fn test_process_404() {
         .mount(&mock_server)
         .await;
 
+    let (_tmp, dir) = state_dir();
     let result = process(
         mock_server.uri(),
         "".to_string(),
         "".to_string(),
         "".to_string(),
         "brog".to_string(),
-        "".to_string(),
+        dir,
     )
     .await;
     assert!(result.is_err())
 }
 
 #[tokio::test]
+#[allow(deprecated)]
 
 async fn test_no_auth_process_request_ok() {
     use std::fs;
@@ -112,20 +193,25 @@ async fn test_no_auth_process_request_ok() {
         .mount(&mock_server)
         .await;
     let uri = format!("{}/brog.yaml", mock_server.uri());
+    let (_tmp, dir) = state_dir();
     let result = process(
         uri,
         "".to_owned(),
         "".to_owned(),
         bootcpath.to_string(),
         "brog".to_string(),
-        "".to_string(),
+        dir,
     )
     .await;
     assert!(result.is_ok());
-    assert_eq!("quay.io/fedora/fedora-bootc:41".to_owned(), result.unwrap())
+    assert_eq!(
+        "quay.io/fedora/fedora-bootc:41",
+        result.unwrap().target_image
+    )
 }
 
 #[tokio::test]
+#[allow(deprecated)]
 async fn test_number_err() {
     use std::fs;
     use std::path::Path;
@@ -147,19 +233,21 @@ async fn test_number_err() {
         .mount(&mock_server)
         .await;
     let uri = format!("{}/brog.yaml", mock_server.uri());
+    let (_tmp, dir) = state_dir();
     let result = process(
         uri,
         "".to_owned(),
         "".to_owned(),
         bootcpath.to_string(),
         "brog".to_string(),
-        "".to_string(),
+        dir,
     )
     .await;
     assert!(result.is_err());
 }
 
 #[tokio::test]
+#[allow(deprecated)]
 
 async fn test_auth_process_request_ok() {
     use chrono::{DateTime, NaiveDateTime, Utc};
@@ -267,20 +355,25 @@ async fn test_auth_process_request_ok() {
         .await;
 
     let uri = format!("{}/brog.yaml", mock_server.uri());
+    let (_tmp, dir) = state_dir();
     let result = process(
         uri,
         "ivegotthekey".to_owned(),
         "ivegotthesecret".to_owned(),
         bootcpath.to_string(),
         "brog".to_string(),
-        "".to_string(),
+        dir,
     )
     .await;
     assert!(result.is_ok());
-    assert_eq!("quay.io/fedora/fedora-bootc:41".to_owned(), result.unwrap())
+    assert_eq!(
+        "quay.io/fedora/fedora-bootc:41",
+        result.unwrap().target_image
+    )
 }
 
 #[tokio::test]
+#[allow(deprecated)]
 
 async fn test_commit_header_ok() {
     use chrono::{DateTime, NaiveDateTime, Utc};
@@ -411,21 +504,24 @@ async fn test_commit_header_ok() {
         .mount(&mock_server)
         .await;
 
-    let path = env::current_dir().unwrap();
     let uri = format!("{}/brog.yaml", mock_server.uri());
+    let (_tmp, dir) = state_dir();
     let result = process(
         uri,
         "ivegotthekey".to_owned(),
         "ivegotthesecret".to_owned(),
         bootcpath.to_string(),
         "brog".to_string(),
-        path.to_string_lossy().to_string(),
+        dir.clone(),
     )
     .await;
     println!("{:#?}", result);
     assert!(result.is_ok());
 
-    assert_eq!("quay.io/fedora/fedora-bootc:41".to_owned(), result.unwrap());
+    assert_eq!(
+        "quay.io/fedora/fedora-bootc:41",
+        result.unwrap().target_image
+    );
     let mock_server_commit = MockServer::start().await;
     let body =
         fs::read_to_string("samples/brog.yaml").expect("Should have been able to read the file");
@@ -444,23 +540,26 @@ async fn test_commit_header_ok() {
         .mount(&mock_server_commit)
         .await;
 
-    let path = env::current_dir().unwrap();
-    let uri = format!("{}/brog.yaml", mock_server.uri());
+    let uri = format!("{}/brog.yaml", mock_server_commit.uri());
     let result = process(
         uri,
         "ivegotthekey".to_owned(),
         "ivegotthesecret".to_owned(),
         bootcpath.to_string(),
         "brog".to_string(),
-        path.to_string_lossy().to_string(),
+        dir.clone(),
     )
     .await;
     println!("{:#?}", result);
     assert!(result.is_ok());
-    assert_eq!("quay.io/fedora/fedora-bootc:41".to_owned(), result.unwrap())
+    assert_eq!(
+        "quay.io/fedora/fedora-bootc:41",
+        result.unwrap().target_image
+    )
 }
 
 #[tokio::test]
+#[allow(deprecated)]
 
 async fn test_no_auth_extended_yaml_request_ok() {
     use std::fs;
@@ -483,15 +582,2255 @@ async fn test_no_auth_extended_yaml_request_ok() {
         .mount(&mock_server)
         .await;
     let uri = format!("{}/brog-extended.yaml", mock_server.uri());
+    let (_tmp, dir) = state_dir();
     let result = process(
         uri,
         "".to_owned(),
         "".to_owned(),
         bootcpath.to_string(),
         "brog".to_string(),
-        "".to_string(),
+        dir,
+    )
+    .await;
+    assert!(result.is_ok());
+    assert_eq!(
+        "quay.io/fedora/fedora-bootc:41",
+        result.unwrap().target_image
+    )
+}
+
+#[test]
+fn test_document_list_and_map_forms() {
+    use brog::{BrogDocument, ClientConfigs};
+    use std::fs;
+
+    let body =
+        fs::read_to_string("samples/brog.yaml").expect("Should have been able to read the file");
+    let list = BrogDocument::from_yaml(&body).unwrap();
+    assert!(matches!(list.client_config, ClientConfigs::List(_)));
+    assert_eq!(brog::config::API_VERSION, list.api_version);
+
+    let body = fs::read_to_string("samples/brog-extended.yaml")
+        .expect("Should have been able to read the file");
+    let map = BrogDocument::from_yaml(&body).unwrap();
+    assert!(matches!(map.client_config, ClientConfigs::Map(_)));
+    let client = map.client().unwrap();
+    assert_eq!("quay.io/fedora/fedora-bootc:41", client.image);
+    assert_eq!(vec![25, 50, 75], client.canary_schedule);
+    assert_eq!(Some(1), client.interval);
+    assert_eq!(Some(0), client.retrycount);
+    assert!(map.unknown_fields().is_empty());
+
+    let roundtrip = BrogDocument::from_yaml(&map.to_yaml().unwrap()).unwrap();
+    assert_eq!(map, roundtrip);
+}
+
+#[test]
+fn test_document_errors_name_yaml_path() {
+    use brog::BrogDocument;
+    use std::fs;
+
+    let body = fs::read_to_string("samples/brog-bad.yaml")
+        .expect("Should have been able to read the file");
+    let err = BrogDocument::from_yaml(&body).unwrap_err().to_string();
+    assert!(err.contains("clientConfig[0].image"), "{}", err);
+
+    let err = BrogDocument::from_yaml("clientConfig:\n  image: a\n  canarySchedule: [50, 25]\n")
+        .unwrap_err()
+        .to_string();
+    assert!(err.contains("clientConfig.canarySchedule[1]"), "{}", err);
+
+    let err = BrogDocument::from_yaml("clientConfig:\n  image: a\n  canarySchedule: [25, 50]\n")
+        .unwrap_err()
+        .to_string();
+    assert!(err.contains("clientConfig.interval"), "{}", err);
+
+    let err =
+        BrogDocument::from_yaml("apiVersion: brog.mehal.tech/v9\nclientConfig:\n  image: a\n")
+            .unwrap_err()
+            .to_string();
+    assert!(err.contains("apiVersion"), "{}", err);
+}
+
+#[test]
+fn test_document_unknown_fields() {
+    use brog::BrogDocument;
+
+    let doc = BrogDocument::from_yaml(
+        "clientConfig:\n- image: a\n  imagePullPolicy: Always\nextra: true\n",
+    )
+    .unwrap();
+    assert_eq!(
+        vec![
+            "extra".to_owned(),
+            "clientConfig[0].imagePullPolicy".to_owned()
+        ],
+        doc.unknown_fields()
+    );
+}
+
+#[test]
+fn test_canary_bucket_is_stable() {
+    use brog::canary::machine_bucket;
+
+    let bucket = machine_bucket("3d1219c7c4c5404aaa1f6d2a48adfda4\n");
+    assert!(bucket < 100);
+    assert_eq!(bucket, machine_bucket("3d1219c7c4c5404aaa1f6d2a48adfda4"));
+    let buckets: std::collections::HashSet<u8> = (0..1000)
+        .map(|i| machine_bucket(&format!("{:032x}", i)))
+        .collect();
+    assert!(buckets.len() > 90);
+}
+
+#[test]
+fn test_canary_stage_advances_on_interval() {
+    use brog::canary::{current_percent, Rollout};
+    use chrono::{Duration, Utc};
+
+    let schedule = [25, 50, 75];
+    assert_eq!(25, current_percent(&schedule, 10, 0));
+    assert_eq!(25, current_percent(&schedule, 10, 9));
+    assert_eq!(50, current_percent(&schedule, 10, 10));
+    assert_eq!(75, current_percent(&schedule, 10, 25));
+    assert_eq!(100, current_percent(&schedule, 10, 30));
+    // Without an interval the rollout holds at the first stage.
+    assert_eq!(25, current_percent(&schedule, 0, 600));
+    assert_eq!(100, current_percent(&[], 10, 0));
+
+    let start = Utc::now() - Duration::minutes(15);
+    let rollout = Rollout::for_image(None, "quay.io/a:1", start);
+    let again = Rollout::for_image(Some(&rollout), "quay.io/a:1", Utc::now());
+    assert_eq!(rollout, again);
+    assert_eq!(50, again.percent(&schedule, 10, Utc::now()));
+    let next = Rollout::for_image(Some(&again), "quay.io/a:2", Utc::now());
+    assert_eq!(25, next.percent(&schedule, 10, Utc::now()));
+}
+
+#[test]
+fn test_bootc_status_parse() {
+    use brog::bootc::BootcHost;
+    use std::path::Path;
+
+    let mut path = env::current_dir().unwrap_or_default();
+    path.push(Path::new("mocks"));
+    let bin_path = path.to_str().unwrap_or_default();
+    let text = run_command_text(vec!["status", "--format", "yaml"], bin_path).unwrap();
+    let host = BootcHost::from_yaml(&text).unwrap();
+
+    let booted = host.booted_image().unwrap();
+    assert_eq!("quay.io/mehal_tech/clos:v0.0.6", booted.image.image);
+    assert_eq!(Some("40.20241023.0".to_owned()), booted.version);
+    assert!(host.staged_image().is_none());
+    assert!(host.rollback_image().is_none());
+
+    assert!(host.is_on("quay.io/mehal_tech/clos:v0.0.6"));
+    assert!(host.is_on(
+        "quay.io/mehal_tech/clos@sha256:ed3e3f40c0db29da93bd7f805640e9f5c5b1aec5d80fbfe29250485260aa8cdf"
+    ));
+    assert!(host.is_on(
+        "quay.io/mehal_tech/clos:v0.0.6@sha256:ed3e3f40c0db29da93bd7f805640e9f5c5b1aec5d80fbfe29250485260aa8cdf"
+    ));
+    assert!(!host.is_on("quay.io/mehal_tech/clos:v0.0.7"));
+    assert!(!host.is_on("quay.io/mehal_tech/clos@sha256:0000"));
+    assert!(!host.is_on("quay.io/mehal_tech/clos:v0.0.6@sha256:0000"));
+    assert!(!host.is_on("quay.io/fedora/fedora-bootc:41"));
+}
+
+#[tokio::test]
+#[allow(deprecated)]
+async fn test_process_skips_switch_when_booted() {
+    use std::path::Path;
+    use wiremock::matchers::method;
+    use wiremock::{Mock, MockServer, ResponseTemplate};
+    let mock_server = MockServer::start().await;
+
+    let rt = ResponseTemplate::new(200)
+        .set_body_string("clientConfig:\n  image: quay.io/mehal_tech/clos:v0.0.6\n");
+    // This mock only answers `bootc status` and fails on anything else.
+    let mut path = env::current_dir().unwrap_or_default();
+    path.push(Path::new("mocks/status"));
+    let bootcpath = path.to_str().unwrap_or_default();
+
+    Mock::given(method("GET"))
+        .and(wiremock::matchers::path("/brog.yaml"))
+        .respond_with(rt)
+        .mount(&mock_server)
+        .await;
+    let uri = format!("{}/brog.yaml", mock_server.uri());
+    let (_tmp, dir) = state_dir();
+    let result = process(
+        uri,
+        "".to_owned(),
+        "".to_owned(),
+        bootcpath.to_string(),
+        "brog".to_string(),
+        dir.clone(),
+    )
+    .await;
+    assert_eq!(
+        "quay.io/mehal_tech/clos:v0.0.6",
+        result.unwrap().target_image
+    );
+
+    let mock_server = MockServer::start().await;
+    Mock::given(method("GET"))
+        .respond_with(
+            ResponseTemplate::new(200)
+                .set_body_string("clientConfig:\n  image: quay.io/fedora/fedora-bootc:41\n"),
+        )
+        .mount(&mock_server)
+        .await;
+    let result = process(
+        mock_server.uri(),
+        "".to_owned(),
+        "".to_owned(),
+        bootcpath.to_string(),
+        "brog".to_string(),
+        dir.clone(),
+    )
+    .await;
+    assert!(result.is_err());
+}
+
+#[tokio::test]
+async fn test_health_check_rolls_back_unhealthy_image() {
+    use brog::health::{verify_pending, HealthCheck, HealthOutcome, PendingCheck};
+    use brog::{State, StateStore};
+    use std::path::Path;
+    use wiremock::matchers::method;
+    use wiremock::{Mock, MockServer, ResponseTemplate};
+
+    let mock_server = MockServer::start().await;
+    Mock::given(method("GET"))
+        .and(wiremock::matchers::path("/healthz"))
+        .respond_with(ResponseTemplate::new(503))
+        .mount(&mock_server)
+        .await;
+
+    let mut path = env::current_dir().unwrap_or_default();
+    path.push(Path::new("mocks"));
+    let bootcpath = path.to_str().unwrap_or_default();
+    let (_service_location_tmp, service_location) = state_dir();
+    let store = StateStore::new(&service_location);
+
+    let check = HealthCheck {
+        http: Some(format!("{}/healthz", mock_server.uri())),
+        deadline: 0,
+        max_boots: 1,
+        ..HealthCheck::default()
+    };
+    let booted = "sha256:ed3e3f40c0db29da93bd7f805640e9f5c5b1aec5d80fbfe29250485260aa8cdf";
+    // A check for another digest of the tag waits for that digest to boot.
+    let state = State {
+        pending_check: Some(PendingCheck {
+            image: "quay.io/mehal_tech/clos:v0.0.6".to_owned(),
+            digest: Some("sha256:0b5a7f1c".to_owned()),
+            check: check.clone(),
+            boots: 0,
+            boot_id: None,
+        }),
+        ..State::default()
+    };
+    store.save(&state).unwrap();
+    let outcome = verify_pending(&service_location, bootcpath).await.unwrap();
+    assert_eq!(HealthOutcome::NotBooted, outcome);
+
+    let state = State {
+        pending_check: Some(PendingCheck {
+            image: "quay.io/mehal_tech/clos:v0.0.6".to_owned(),
+            digest: Some(booted.to_owned()),
+            check,
+            boots: 0,
+            boot_id: None,
+        }),
+        ..State::default()
+    };
+    store.save(&state).unwrap();
+
+    let outcome = verify_pending(&service_location, bootcpath).await.unwrap();
+    assert!(matches!(outcome, HealthOutcome::RolledBack { .. }));
+    let state = store.load().unwrap();
+    assert!(state.pending_check.is_none());
+    assert_eq!(1, state.rollbacks.len());
+    assert!(state.is_rolled_back("quay.io/mehal_tech/clos:v0.0.6", Some(booted)));
+    assert!(state.is_rolled_back("quay.io/mehal_tech/clos:v0.0.6", None));
+    // A later digest of the same tag may be tried.
+    assert!(!state.is_rolled_back("quay.io/mehal_tech/clos:v0.0.6", Some("sha256:0b5a7f1c")));
+
+    let outcome = verify_pending(&service_location, bootcpath).await.unwrap();
+    assert_eq!(HealthOutcome::NothingPending, outcome);
+}
+
+#[tokio::test]
+async fn test_health_check_passes() {
+    use brog::health::HealthCheck;
+    use wiremock::matchers::method;
+    use wiremock::{Mock, MockServer, ResponseTemplate};
+
+    let mock_server = MockServer::start().await;
+    Mock::given(method("GET"))
+        .respond_with(ResponseTemplate::new(200))
+        .mount(&mock_server)
+        .await;
+
+    let check = HealthCheck {
+        http: Some(mock_server.uri()),
+        script: Some("/bin/true".to_owned()),
+        deadline: 0,
+        ..HealthCheck::default()
+    };
+    assert!(check
+        .run("/usr/bin:/bin", &reqwest::Client::new())
+        .await
+        .is_ok());
+
+    let check = HealthCheck {
+        script: Some("/bin/false".to_owned()),
+        deadline: 0,
+        ..HealthCheck::default()
+    };
+    assert!(check
+        .run("/usr/bin:/bin", &reqwest::Client::new())
+        .await
+        .is_err());
+
+    let err = brog::BrogDocument::from_yaml(
+        "clientConfig:\n  image: a\n  healthCheck:\n    http: http://example.com/\n",
+    )
+    .unwrap_err();
+    assert!(err.to_string().contains("clientConfig.healthCheck.http"));
+}
+
+#[tokio::test]
+async fn test_health_check_timeout_and_failed_rollback() {
+    use brog::health::{HealthCheck, HealthOutcome, PendingCheck};
+    use brog::{Agent, BrogConfig, FakeDriver, Metrics, State, StateStore};
+    use std::sync::Arc;
+    use std::time::{Duration, Instant};
+
+    // A hung check is killed once the deadline has passed.
+    let hang = env::current_dir().unwrap().join("mocks/hang/check");
+    let check = HealthCheck {
+        script: Some(hang.to_str().unwrap().to_owned()),
+        deadline: 0,
+        ..HealthCheck::default()
+    };
+    let started = Instant::now();
+    let err = check
+        .run("/usr/bin:/bin", &reqwest::Client::new())
+        .await
+        .unwrap_err();
+    assert!(err.to_string().contains("did not finish"));
+    assert!(started.elapsed() < Duration::from_secs(20));
+
+    let (_tmp, dir) = state_dir();
+    let store = StateStore::new(&dir);
+    store
+        .save(&State {
+            pending_check: Some(PendingCheck {
+                image: "quay.io/mehal_tech/clos:v0.0.6".to_owned(),
+                digest: Some("sha256:06".to_owned()),
+                check: HealthCheck {
+                    script: Some("/bin/false".to_owned()),
+                    deadline: 0,
+                    ..HealthCheck::default()
+                },
+                boots: 0,
+                boot_id: None,
+            }),
+            ..State::default()
+        })
+        .unwrap();
+    let config = BrogConfig::builder("https://example.com/brog.yaml")
+        .config_path(&dir)
+        .build()
+        .unwrap();
+    let mut host = FakeDriver::booted("quay.io/mehal_tech/clos:v0.0.6", "sha256:06").host();
+    host.status.rollback = FakeDriver::booted("quay.io/mehal_tech/clos:v0.0.5", "sha256:05")
+        .host()
+        .status
+        .booted;
+    let fake = Arc::new(FakeDriver::new(host));
+    let metrics = Arc::new(Metrics::default());
+    let agent = Agent::new(config)
+        .unwrap()
+        .with_driver(fake.clone())
+        .with_metrics(metrics.clone());
+
+    // A failed rollback is not recorded and is tried again.
+    fake.fail_next("rollback failed");
+    assert!(agent.verify_health().await.is_err());
+    let state = store.load().unwrap();
+    assert!(state.pending_check.is_some());
+    assert!(state.rollbacks.is_empty());
+    assert_eq!("rollbackFailed", state.history.last().unwrap().event);
+
+    let outcome = agent.verify_health().await.unwrap();
+    assert!(
+        matches!(&outcome, HealthOutcome::RolledBack { image, rolled_back_to: Some(to), .. }
+            if image == "quay.io/mehal_tech/clos:v0.0.6" && to == "quay.io/mehal_tech/clos:v0.0.5@sha256:05"),
+        "{:?}",
+        outcome
+    );
+    let state = store.load().unwrap();
+    assert!(state.pending_check.is_none());
+    assert!(state.is_rolled_back("quay.io/mehal_tech/clos:v0.0.6", Some("sha256:06")));
+    assert_eq!(vec!["rollback --apply", "rollback --apply"], fake.calls());
+    let rendered = metrics.render();
+    assert!(rendered.contains("brog_reconciles_total{outcome=\"rolledBack\"} 1"));
+    assert!(rendered.contains("brog_rollbacks_total 1"));
+}
+
+#[test]
+fn test_state_store_roundtrip_and_legacy_sha() {
+    use brog::{State, StateStore};
+    use std::fs;
+
+    let (_tmp, dir) = state_dir();
+    fs::write(format!("{}/sha", dir), "abcdef\n").unwrap();
+    let store = StateStore::new(&dir);
+    assert_eq!(Some("abcdef".to_owned()), store.load().unwrap().commit);
+
+    let state = State {
+        applied_image: Some("quay.io/a:1".to_owned()),
+        attempts: 2,
+        ..store.load().unwrap()
+    };
+    store.save(&state).unwrap();
+    assert_eq!(state, store.load().unwrap());
+    let temp_files = || {
+        fs::read_dir(&dir)
+            .unwrap()
+            .filter(|e| {
+                let name = e.as_ref().unwrap().file_name();
+                name.to_string_lossy().ends_with(".tmp")
+            })
+            .count()
+    };
+    assert_eq!(0, temp_files());
+
+    // Concurrent writers each rename a complete file into place.
+    let writers: Vec<_> = (0..8)
+        .map(|n| {
+            let dir = dir.clone();
+            std::thread::spawn(move || {
+                let store = StateStore::new(&dir);
+                for attempts in 0..20 {
+                    let state = State {
+                        applied_image: Some(format!("quay.io/a:{}", n)),
+                        attempts,
+                        ..State::default()
+                    };
+                    store.save(&state).unwrap();
+                }
+            })
+        })
+        .collect();
+    for writer in writers {
+        writer.join().unwrap();
+    }
+    assert_eq!(19, store.load().unwrap().attempts);
+    assert_eq!(0, temp_files());
+    store.save(&state).unwrap();
+
+    let updated = store.update(|s| s.attempts = 0).unwrap();
+    assert_eq!(0, updated.attempts);
+    assert_eq!(updated, store.load().unwrap());
+
+    // An empty legacy sha file must not become an empty commit
+    fs::remove_file(format!("{}/state.json", dir)).unwrap();
+    fs::write(format!("{}/sha", dir), "").unwrap();
+    assert_eq!(None, store.load().unwrap().commit);
+}
+
+#[tokio::test]
+#[allow(deprecated)]
+async fn test_process_records_state() {
+    use brog::StateStore;
+    use std::fs;
+    use std::path::Path;
+    use wiremock::matchers::method;
+    use wiremock::{Mock, MockServer, ResponseTemplate};
+    let mock_server = MockServer::start().await;
+    let body =
+        fs::read_to_string("samples/brog.yaml").expect("Should have been able to read the file");
+
+    Mock::given(method("GET"))
+        .respond_with(
+            ResponseTemplate::new(200)
+                .append_header("x-clos-commit", "654321")
+                .set_body_string(body),
+        )
+        .mount(&mock_server)
+        .await;
+    let mut path = env::current_dir().unwrap_or_default();
+    path.push(Path::new("mocks"));
+    let bootcpath = path.to_str().unwrap_or_default();
+
+    let (_tmp, dir) = state_dir();
+    let result = process(
+        mock_server.uri(),
+        "".to_owned(),
+        "".to_owned(),
+        bootcpath.to_string(),
+        "brog".to_string(),
+        dir.clone(),
     )
     .await;
     assert!(result.is_ok());
-    assert_eq!("quay.io/fedora/fedora-bootc:41".to_owned(), result.unwrap())
+    let state = StateStore::new(&dir).load().unwrap();
+    assert_eq!(Some("654321".to_owned()), state.commit);
+    assert_eq!(
+        Some("quay.io/fedora/fedora-bootc:41".to_owned()),
+        state.applied_image
+    );
+    assert!(state.last_fetch.is_some());
+    assert!(state.last_success.is_some());
+    assert!(state.last_failure.is_none());
+
+    let mut path = env::current_dir().unwrap_or_default();
+    path.push(Path::new("mocks/error"));
+    let result = process(
+        mock_server.uri(),
+        "".to_owned(),
+        "".to_owned(),
+        path.to_string_lossy().to_string(),
+        "brog".to_string(),
+        dir.clone(),
+    )
+    .await;
+    assert!(result.is_err());
+    let state = StateStore::new(&dir).load().unwrap();
+    assert!(state.last_failure.is_some());
+    assert!(state.last_error.is_some());
+}
+
+#[tokio::test]
+async fn test_dry_run_reports_plan() {
+    use std::path::Path;
+    use wiremock::matchers::method;
+    use wiremock::{Mock, MockServer, ResponseTemplate};
+    let mock_server = MockServer::start().await;
+
+    Mock::given(method("GET"))
+        .respond_with(
+            ResponseTemplate::new(200)
+                .set_body_string("clientConfig:\n  image: quay.io/fedora/fedora-bootc:41\n"),
+        )
+        .mount(&mock_server)
+        .await;
+    // This mock only answers `bootc status` and fails on anything else.
+    let mut path = env::current_dir().unwrap_or_default();
+    path.push(Path::new("mocks/status"));
+    let bootcpath = path.to_str().unwrap_or_default();
+
+    let (_tmp, dir) = state_dir();
+    let plan = reconcile(test_config(&mock_server.uri(), bootcpath, &dir).dry_run(true))
+        .await
+        .unwrap()
+        .plan();
+    assert!(plan.switch);
+    assert!(plan.dry_run);
+    assert_eq!(
+        "would switch from quay.io/mehal_tech/clos:v0.0.6@sha256:ed3e3f40c0db29da93bd7f805640e9f5c5b1aec5d80fbfe29250485260aa8cdf to quay.io/fedora/fedora-bootc:41",
+        plan.to_string()
+    );
+    assert!(!Path::new(&format!("{}/state.json", dir)).exists());
+}
+
+#[tokio::test]
+#[allow(deprecated)]
+async fn test_conditional_fetch_not_modified() {
+    use std::fs;
+    use std::path::Path;
+    use wiremock::matchers::{header, header_exists, method};
+    use wiremock::{Mock, MockServer, ResponseTemplate};
+    let mock_server = MockServer::start().await;
+    let body =
+        fs::read_to_string("samples/brog.yaml").expect("Should have been able to read the file");
+
+    Mock::given(method("GET"))
+        .and(header("if-none-match", "\"v1\""))
+        .and(header_exists("if-modified-since"))
+        .respond_with(ResponseTemplate::new(304))
+        .with_priority(1)
+        .expect(1)
+        .mount(&mock_server)
+        .await;
+    Mock::given(method("GET"))
+        .respond_with(
+            ResponseTemplate::new(200)
+                .append_header("etag", "\"v1\"")
+                .append_header("last-modified", "Wed, 21 Oct 2015 07:28:00 GMT")
+                .set_body_string(body),
+        )
+        .with_priority(2)
+        .expect(1)
+        .mount(&mock_server)
+        .await;
+
+    let mut path = env::current_dir().unwrap_or_default();
+    path.push(Path::new("mocks"));
+    let bootcpath = path.to_str().unwrap_or_default();
+    let (_tmp, dir) = state_dir();
+
+    for _ in 0..2 {
+        let result = process(
+            mock_server.uri(),
+            "".to_owned(),
+            "".to_owned(),
+            bootcpath.to_string(),
+            "brog".to_string(),
+            dir.clone(),
+        )
+        .await;
+        assert_eq!(
+            "quay.io/fedora/fedora-bootc:41",
+            result.unwrap().target_image
+        );
+    }
+}
+
+#[tokio::test]
+async fn test_fetch_retries_transient_errors() {
+    use brog::{retry::RetryPolicy, StateStore};
+    use std::fs;
+    use std::path::Path;
+    use std::time::Duration;
+    use wiremock::matchers::method;
+    use wiremock::{Mock, MockServer, ResponseTemplate};
+    let mock_server = MockServer::start().await;
+    let body =
+        fs::read_to_string("samples/brog.yaml").expect("Should have been able to read the file");
+
+    Mock::given(method("GET"))
+        .respond_with(ResponseTemplate::new(503))
+        .up_to_n_times(1)
+        .with_priority(1)
+        .mount(&mock_server)
+        .await;
+    Mock::given(method("GET"))
+        .respond_with(ResponseTemplate::new(429).append_header("retry-after", "0"))
+        .up_to_n_times(1)
+        .with_priority(2)
+        .mount(&mock_server)
+        .await;
+    Mock::given(method("GET"))
+        .respond_with(ResponseTemplate::new(200).set_body_string(body))
+        .with_priority(3)
+        .mount(&mock_server)
+        .await;
+
+    let mut path = env::current_dir().unwrap_or_default();
+    path.push(Path::new("mocks"));
+    let bootcpath = path.to_str().unwrap_or_default();
+    let (_tmp, dir) = state_dir();
+    let config = |endpoint: &str| {
+        test_config(endpoint, bootcpath, &dir).fetch_retry(RetryPolicy {
+            max_attempts: 3,
+            base_delay: Duration::from_millis(10),
+            max_delay: Duration::from_millis(50),
+        })
+    };
+    let result = reconcile(config(&mock_server.uri())).await;
+    assert!(result.is_ok());
+    assert_eq!(3, StateStore::new(&dir).load().unwrap().fetch_attempts);
+
+    let mock_server = MockServer::start().await;
+    Mock::given(method("GET"))
+        .respond_with(ResponseTemplate::new(404))
+        .expect(1)
+        .mount(&mock_server)
+        .await;
+    let result = reconcile(config(&mock_server.uri())).await;
+    assert!(result.is_err());
+    assert_eq!(1, StateStore::new(&dir).load().unwrap().fetch_attempts);
+}
+
+#[test]
+fn test_retry_backoff_is_bounded() {
+    use brog::retry::{retry_after, RetryPolicy};
+    use reqwest::header::{HeaderMap, HeaderValue, RETRY_AFTER};
+    use std::time::Duration;
+
+    let policy = RetryPolicy {
+        max_attempts: 10,
+        base_delay: Duration::from_millis(100),
+        max_delay: Duration::from_secs(1),
+    };
+    assert_eq!(Duration::from_millis(100), policy.ceiling(1));
+    assert_eq!(Duration::from_millis(400), policy.ceiling(3));
+    assert_eq!(Duration::from_secs(1), policy.ceiling(8));
+    assert_eq!(Duration::from_secs(1), policy.ceiling(100));
+    for attempt in 1..10 {
+        assert!(policy.delay(attempt, None) <= policy.ceiling(attempt));
+    }
+    assert_eq!(
+        Duration::from_secs(1),
+        policy.delay(1, Some(Duration::from_secs(120)))
+    );
+
+    let mut headers = HeaderMap::new();
+    headers.insert(RETRY_AFTER, HeaderValue::from_static("7"));
+    assert_eq!(Some(Duration::from_secs(7)), retry_after(&headers));
+    headers.insert(
+        RETRY_AFTER,
+        HeaderValue::from_static("Wed, 21 Oct 2015 07:28:00 GMT"),
+    );
+    assert_eq!(None, retry_after(&headers));
+}
+
+#[test]
+fn test_schedule_splay() {
+    use brog::splay::Splay;
+    use std::time::Duration;
+
+    let window = Duration::from_secs(300);
+    assert_eq!(Splay::Machine(window), Splay::parse("300").unwrap());
+    assert_eq!(Splay::Machine(window), Splay::parse("machine:300").unwrap());
+    assert_eq!(Splay::Random(window), Splay::parse("random:300").unwrap());
+    assert_eq!(Splay::None, Splay::parse("0").unwrap());
+    assert!(Splay::parse("5m").is_err());
+    assert!(Splay::parse("sometimes:300").is_err());
+
+    let splay = Splay::Machine(window);
+    let first = splay.delay("4d1b5c6e0f2a4b8c9d0e1f2a3b4c5d6e");
+    assert_eq!(first, splay.delay("4d1b5c6e0f2a4b8c9d0e1f2a3b4c5d6e\n"));
+    assert!(first < window);
+    let offsets: std::collections::HashSet<_> = (0..100)
+        .map(|i| splay.delay(&format!("machine-{}", i)).as_secs())
+        .collect();
+    assert!(offsets.len() > 50);
+
+    let splay = Splay::Random(window);
+    assert!((0..100).all(|_| splay.delay("") < window));
+    assert_eq!(Duration::ZERO, Splay::None.delay("machine"));
+}
+
+#[test]
+fn test_maintenance_windows() {
+    use brog::window::{is_open, MaintenanceWindow};
+    use brog::BrogDocument;
+    use chrono::{TimeZone, Utc, Weekday};
+
+    let windows =
+        MaintenanceWindow::parse_list("Mon-Fri 02:00-04:00 Europe/Berlin; Sat,Sun 22:00-06:00")
+            .unwrap();
+    assert_eq!(
+        vec![
+            Weekday::Mon,
+            Weekday::Tue,
+            Weekday::Wed,
+            Weekday::Thu,
+            Weekday::Fri
+        ],
+        windows[0].days
+    );
+    assert_eq!(Some("Europe/Berlin".to_owned()), windows[0].timezone);
+    assert_eq!(None, windows[1].timezone);
+
+    // Wednesday 01:30 UTC is 02:30 in Berlin.
+    let wednesday = Utc.with_ymd_and_hms(2024, 12, 11, 1, 30, 0).unwrap();
+    assert!(windows[0].contains(wednesday));
+    assert!(!windows[0].contains(Utc.with_ymd_and_hms(2024, 12, 11, 3, 30, 0).unwrap()));
+    // Saturday's window runs into Sunday morning, Sunday's into Monday.
+    assert!(windows[1].contains(Utc.with_ymd_and_hms(2024, 12, 14, 23, 0, 0).unwrap()));
+    assert!(windows[1].contains(Utc.with_ymd_and_hms(2024, 12, 16, 5, 0, 0).unwrap()));
+    assert!(!windows[1].contains(Utc.with_ymd_and_hms(2024, 12, 13, 23, 0, 0).unwrap()));
+    assert!(is_open(&windows, wednesday));
+    assert!(is_open(&[], wednesday));
+
+    assert!(MaintenanceWindow::parse("Mon-Fri 02:00").is_err());
+    assert!(MaintenanceWindow::parse("Someday 02:00-04:00").is_err());
+    assert!(MaintenanceWindow::parse("02:00-04:00 Mars/Olympus").is_err());
+
+    let document = BrogDocument::from_yaml(
+        "clientConfig:
+  image: quay.io/fedora/fedora-bootc:41
+  maintenanceWindows:
+  - days: [Sat, Sun]
+    start: \"01:00\"
+    end: \"05:00\"
+    timezone: America/New_York
+",
+    )
+    .unwrap();
+    let window = &document.client().unwrap().maintenance_windows[0];
+    assert_eq!(vec![Weekday::Sat, Weekday::Sun], window.days);
+    assert_eq!(
+        "America/New_York",
+        window.timezone.as_deref().unwrap_or_default()
+    );
+
+    let error = BrogDocument::from_yaml(
+        "clientConfig:
+  image: quay.io/fedora/fedora-bootc:41
+  maintenanceWindows:
+  - start: \"01:00\"
+    end: \"05:00\"
+    timezone: Nowhere
+",
+    )
+    .unwrap_err();
+    assert!(error
+        .to_string()
+        .contains("clientConfig.maintenanceWindows[0].timezone"));
+}
+
+#[tokio::test]
+async fn test_maintenance_window_gates_apply() {
+    use brog::{window::MaintenanceWindow, StateStore};
+    use std::path::Path;
+    use wiremock::matchers::method;
+    use wiremock::{Mock, MockServer, ResponseTemplate};
+    let mock_server = MockServer::start().await;
+    Mock::given(method("GET"))
+        .respond_with(
+            ResponseTemplate::new(200)
+                .set_body_string("clientConfig:\n  image: quay.io/fedora/fedora-bootc:41\n"),
+        )
+        .mount(&mock_server)
+        .await;
+
+    let now = chrono::Utc::now();
+    let closed = MaintenanceWindow::parse(&format!(
+        "{}-{}",
+        (now + chrono::Duration::hours(2)).format("%H:%M"),
+        (now + chrono::Duration::hours(3)).format("%H:%M")
+    ))
+    .unwrap();
+    let open = MaintenanceWindow::parse("00:00-00:00").unwrap();
+    let run = |bootc: &str, dir: &str, window: &MaintenanceWindow| {
+        let path = env::current_dir()
+            .unwrap_or_default()
+            .join(Path::new(bootc));
+        let config = test_config(&mock_server.uri(), path.to_str().unwrap(), dir)
+            .maintenance_windows(vec![window.clone()]);
+        async move { reconcile(config).await.map(|outcome| outcome.plan()) }
+    };
+
+    // Outside the window the image is only staged.
+    let (_tmp, dir) = state_dir();
+    let plan = run("mocks", &dir, &closed).await.unwrap();
+    assert!(plan.switch);
+    assert!(!plan.apply);
+    let state = StateStore::new(&dir).load().unwrap();
+    assert_eq!("staged", state.history.last().unwrap().event);
+
+    // Once staged, brog waits for the window and then reboots.
+    let (_tmp, dir) = state_dir();
+    let plan = run("mocks/staged", &dir, &closed).await.unwrap();
+    assert!(!plan.switch);
+    assert_eq!("staged, waiting for a maintenance window", plan.reason);
+    let plan = run("mocks/staged", &dir, &open).await.unwrap();
+    assert!(plan.switch);
+    assert!(plan.apply);
+    let state = StateStore::new(&dir).load().unwrap();
+    assert_eq!("applied", state.history.last().unwrap().event);
+}
+
+#[tokio::test]
+async fn test_stage_only_waits_for_apply() {
+    use brog::StateStore;
+    use std::path::Path;
+    use wiremock::matchers::method;
+    use wiremock::{Mock, MockServer, ResponseTemplate};
+    let mock_server = MockServer::start().await;
+    Mock::given(method("GET"))
+        .respond_with(
+            ResponseTemplate::new(200)
+                .set_body_string("clientConfig:\n  image: quay.io/fedora/fedora-bootc:41\n"),
+        )
+        .mount(&mock_server)
+        .await;
+    let bootc = |dir: &str| {
+        let mut path = env::current_dir().unwrap_or_default();
+        path.push(Path::new(dir));
+        path.to_str().unwrap_or_default().to_owned()
+    };
+    let (_tmp, dir) = state_dir();
+    let agent = |mock: &str| {
+        let config = test_config(&mock_server.uri(), &bootc(mock), &dir)
+            .stage_only(true)
+            .build()
+            .unwrap();
+        Agent::new(config).unwrap()
+    };
+    let plan = agent("mocks").reconcile().await.unwrap().plan();
+    assert_eq!(
+        "staged quay.io/fedora/fedora-bootc:41: waiting for brog apply",
+        plan.to_string()
+    );
+    // Nothing is staged on this host.
+    assert!(matches!(
+        agent("mocks").apply_staged().await,
+        Err(BrogError::NotStaged { staged: None, .. })
+    ));
+
+    let plan = agent("mocks/staged").reconcile().await.unwrap().plan();
+    assert!(!plan.switch);
+    assert_eq!("staged, waiting for brog apply", plan.reason);
+
+    // Turning stage-only off reboots into the staged deployment.
+    let config = test_config(&mock_server.uri(), &bootc("mocks/staged"), &dir)
+        .dry_run(true)
+        .build()
+        .unwrap();
+    let plan = Agent::new(config)
+        .unwrap()
+        .reconcile()
+        .await
+        .unwrap()
+        .plan();
+    assert!(plan.apply);
+    assert_eq!("applying staged deployment", plan.reason);
+
+    let plan = agent("mocks/staged").apply_staged().await.unwrap();
+    assert!(plan.apply);
+    assert_eq!("quay.io/fedora/fedora-bootc:41", plan.to);
+    let state = StateStore::new(&dir).load().unwrap();
+    assert_eq!("applied", state.history.last().unwrap().event);
+
+    // A staged image that is no longer the target is not applied.
+    StateStore::new(&dir)
+        .update(|state| state.target_image = Some("quay.io/fedora/fedora-bootc:42".to_owned()))
+        .unwrap();
+    let err = agent("mocks/staged").apply_staged().await.unwrap_err();
+    assert!(
+        matches!(&err, BrogError::NotStaged { staged: Some(_), target: Some(target) } if target == "quay.io/fedora/fedora-bootc:42"),
+        "{:?}",
+        err
+    );
+}
+
+#[tokio::test]
+async fn test_signed_report_after_reconcile() {
+    use sha2::{Digest, Sha256};
+    use std::path::Path;
+    use wiremock::matchers::{body_partial_json, header_exists, method, path};
+    use wiremock::{Mock, MockServer, ResponseTemplate};
+    let mock_server = MockServer::start().await;
+    Mock::given(method("GET"))
+        .respond_with(
+            ResponseTemplate::new(200)
+                .set_body_string("clientConfig:\n  image: quay.io/mehal_tech/clos:v0.0.6\n"),
+        )
+        .mount(&mock_server)
+        .await;
+    Mock::given(method("POST"))
+        .and(path("/report"))
+        .and(header_exists("authorization"))
+        .and(body_partial_json(serde_json::json!({
+            "result": "success",
+            "switch": false,
+            "reason": "already booted",
+            "bootedImage": "quay.io/mehal_tech/clos:v0.0.6",
+            "bootedDigest": "sha256:ed3e3f40c0db29da93bd7f805640e9f5c5b1aec5d80fbfe29250485260aa8cdf",
+            "targetImage": "quay.io/mehal_tech/clos:v0.0.6",
+        })))
+        .respond_with(ResponseTemplate::new(204))
+        .expect(1)
+        .mount(&mock_server)
+        .await;
+
+    let mut bootcpath = env::current_dir().unwrap_or_default();
+    bootcpath.push(Path::new("mocks"));
+    let (_tmp, dir) = state_dir();
+    let config = test_config(&mock_server.uri(), bootcpath.to_str().unwrap(), &dir)
+        .service_key("key")
+        .service_secret("secret")
+        .report_url(&format!("{}/report", mock_server.uri()));
+    assert!(reconcile(config).await.is_ok());
+
+    let requests = mock_server.received_requests().await.unwrap();
+    let report = requests
+        .iter()
+        .find(|r| r.method.as_str() == "POST")
+        .unwrap();
+    assert_eq!(
+        hex::encode(Sha256::digest(&report.body)),
+        report.headers["x-mhl-content-sha256"].to_str().unwrap()
+    );
+}
+
+#[test]
+fn test_signer_hashes_payload() {
+    use brog::signer::{Signer, UNSIGNED_PAYLOAD};
+
+    let signer = Signer::new("key", "secret", "projects")
+        .with_region("eu-west")
+        .with_identity("4d1b5c6e0f2a4b8c\n", "device-1\n");
+    assert!(signer.is_enabled());
+    assert_eq!("eu-west", signer.region());
+    assert!(!format!("{:?}", signer).contains("secret"));
+
+    let body = br#"{"result":"success"}"#;
+    let headers = signer
+        .sign("POST", "https://example.com/report", Some(body))
+        .unwrap();
+    assert_eq!(
+        Signer::payload_hash(body),
+        headers["x-mhl-content-sha256"].to_str().unwrap()
+    );
+    assert_eq!(64, Signer::payload_hash(body).len());
+    assert_eq!("4d1b5c6e0f2a4b8c", headers["x-mhl-mid"].to_str().unwrap());
+    assert_eq!("device-1", headers["x-mhl-hostname"].to_str().unwrap());
+    assert!(headers.contains_key("authorization"));
+
+    let get = signer
+        .sign("GET", "https://example.com/brog.yaml", None)
+        .unwrap();
+    assert_eq!(
+        UNSIGNED_PAYLOAD,
+        get["x-mhl-content-sha256"].to_str().unwrap()
+    );
+    assert_ne!(get["x-mhl-nonce"], headers["x-mhl-nonce"]);
+    assert!(!Signer::new("key", "", "projects").is_enabled());
+}
+
+#[tokio::test]
+async fn test_config_signature_verification() {
+    use base64::{engine::general_purpose::STANDARD, Engine};
+    use brog::StateStore;
+    use ed25519_dalek::{Signer, SigningKey};
+    use std::path::Path;
+    use wiremock::matchers::{method, path};
+    use wiremock::{Mock, MockServer, ResponseTemplate};
+
+    let body = "clientConfig:\n  image: quay.io/mehal_tech/clos:v0.0.6\n";
+    let key = SigningKey::from_bytes(&[7u8; 32]);
+    let signature = STANDARD.encode(key.sign(body.as_bytes()).to_bytes());
+    let forged = STANDARD.encode(
+        SigningKey::from_bytes(&[8u8; 32])
+            .sign(body.as_bytes())
+            .to_bytes(),
+    );
+
+    let (_keys_tmp, keys) = state_dir();
+    std::fs::write(
+        format!("{}/release.pub", keys),
+        format!(
+            "# release key\n{}\n",
+            STANDARD.encode(key.verifying_key().to_bytes())
+        ),
+    )
+    .unwrap();
+
+    let mock_server = MockServer::start().await;
+    Mock::given(method("GET"))
+        .and(path("/header.yaml"))
+        .respond_with(
+            ResponseTemplate::new(200)
+                .set_body_string(body)
+                .append_header("x-brog-signature", signature.as_str()),
+        )
+        .mount(&mock_server)
+        .await;
+    Mock::given(method("GET"))
+        .and(path("/sibling.yaml"))
+        .respond_with(ResponseTemplate::new(200).set_body_string(body))
+        .mount(&mock_server)
+        .await;
+    Mock::given(method("GET"))
+        .and(path("/sibling.yaml.sig"))
+        .respond_with(ResponseTemplate::new(200).set_body_string(format!("{}\n", signature)))
+        .mount(&mock_server)
+        .await;
+    Mock::given(method("GET"))
+        .and(path("/forged.yaml"))
+        .respond_with(
+            ResponseTemplate::new(200)
+                .set_body_string(body)
+                .append_header("x-brog-signature", forged.as_str()),
+        )
+        .mount(&mock_server)
+        .await;
+    Mock::given(method("GET"))
+        .and(path("/unsigned.yaml"))
+        .respond_with(ResponseTemplate::new(200).set_body_string(body))
+        .mount(&mock_server)
+        .await;
+
+    let mut bootcpath = env::current_dir().unwrap_or_default();
+    bootcpath.push(Path::new("mocks"));
+    let (_tmp, dir) = state_dir();
+    let run = |name: &str| {
+        reconcile(
+            test_config(
+                &format!("{}/{}", mock_server.uri(), name),
+                bootcpath.to_str().unwrap(),
+                &dir,
+            )
+            .signature_keys(&keys),
+        )
+    };
+
+    assert!(run("header.yaml").await.is_ok());
+    assert!(run("sibling.yaml").await.is_ok());
+    for name in ["forged.yaml", "unsigned.yaml"] {
+        let error = run(name).await.unwrap_err();
+        assert!(error
+            .to_string()
+            .starts_with("Config signature verification failed"));
+        let state = StateStore::new(&dir).load().unwrap();
+        assert_eq!("configRejected", state.history.last().unwrap().event);
+        assert!(state.last_error.is_some());
+    }
+}
+
+#[test]
+fn test_image_reference_parse() {
+    use brog::image::ImageRef;
+
+    let image = ImageRef::parse("quay.io/fedora/fedora-bootc:41").unwrap();
+    assert_eq!("quay.io", image.registry);
+    assert_eq!("fedora/fedora-bootc", image.repository);
+    assert_eq!(Some("41".to_owned()), image.tag);
+    assert_eq!(None, image.digest);
+
+    let image = ImageRef::parse("localhost:5000/os@sha256:0b5a7f1c").unwrap();
+    assert_eq!("localhost:5000", image.registry);
+    assert_eq!("os", image.repository);
+    assert_eq!(None, image.tag);
+    assert_eq!(Some("sha256:0b5a7f1c".to_owned()), image.digest);
+    assert_eq!("localhost:5000/os@sha256:0b5a7f1c", image.to_string());
+
+    let image = ImageRef::parse("fedora").unwrap();
+    assert_eq!("docker.io/library/fedora", image.name());
+
+    assert!(ImageRef::parse("quay.io/os@sha256").is_err());
+    assert!(ImageRef::parse("quay.io/os:").is_err());
+    assert!(ImageRef::parse("quay.io//os").is_err());
+}
+
+#[tokio::test]
+async fn test_image_policy_rejects_image() {
+    use brog::{policy::ImagePolicy, Outcome, StateStore};
+    use std::path::Path;
+    use wiremock::matchers::method;
+    use wiremock::{Mock, MockServer, ResponseTemplate};
+
+    let policy = ImagePolicy::from_yaml(
+        "allowedRegistries: [quay.io]
+allowedRepositories: [quay.io/fedora/fedora-bootc, quay.io/mehal_tech/*]
+forbiddenTags: [latest]
+",
+    )
+    .unwrap();
+    assert!(policy.check("quay.io/fedora/fedora-bootc:41").is_ok());
+    assert!(policy.check("quay.io/mehal_tech/clos:v0.0.6").is_ok());
+    assert!(policy
+        .check("docker.io/fedora/fedora-bootc:41")
+        .unwrap_err()
+        .to_string()
+        .contains("registry docker.io is not in allowedRegistries"));
+    assert!(policy
+        .check("quay.io/fedora/fedora:41")
+        .unwrap_err()
+        .to_string()
+        .contains("not in allowedRepositories"));
+    assert!(policy.check("quay.io/fedora/fedora-bootc:latest").is_err());
+    assert!(policy.check("quay.io/fedora/fedora-bootc").is_err());
+    let pinned = ImagePolicy {
+        require_digest: true,
+        ..policy
+    };
+    assert!(pinned.check("quay.io/fedora/fedora-bootc:41").is_err());
+    assert!(pinned
+        .check("quay.io/fedora/fedora-bootc@sha256:0b5a7f1c")
+        .is_ok());
+    assert!(ImagePolicy::from_yaml("allowRegistries: [quay.io]\n").is_err());
+
+    let mock_server = MockServer::start().await;
+    Mock::given(method("GET"))
+        .respond_with(
+            ResponseTemplate::new(200)
+                .set_body_string("clientConfig:\n  image: quay.io/fedora/fedora-bootc:latest\n"),
+        )
+        .mount(&mock_server)
+        .await;
+    let (_tmp, dir) = state_dir();
+    let policy_path = format!("{}/policy.yaml", dir);
+    std::fs::write(&policy_path, "forbiddenTags: [latest]\n").unwrap();
+    // This mock fails on anything but `bootc status`.
+    let mut bootcpath = env::current_dir().unwrap_or_default();
+    bootcpath.push(Path::new("mocks/status"));
+    let config = test_config(&mock_server.uri(), bootcpath.to_str().unwrap(), &dir)
+        .policy_path(Some(&policy_path));
+    let outcome = reconcile(config).await.unwrap();
+    assert_eq!(
+        Outcome::Rejected {
+            policy: "imagePolicy".to_owned(),
+            reason:
+                "Image policy rejects quay.io/fedora/fedora-bootc:latest: tag latest is forbidden"
+                    .to_owned()
+        },
+        outcome.outcome
+    );
+    let state = StateStore::new(&dir).load().unwrap();
+    assert_eq!("policyRejected", state.history.last().unwrap().event);
+}
+
+#[tokio::test]
+async fn test_downgrade_protection() {
+    use brog::version::{compare, is_downgrade};
+    use brog::{Outcome, StateStore};
+    use std::cmp::Ordering;
+    use std::path::Path;
+    use std::sync::Arc;
+    use wiremock::matchers::{method, path};
+    use wiremock::{Mock, MockServer, ResponseTemplate};
+
+    assert_eq!(Ordering::Less, compare("40.20240901.0", "40.20241023.0"));
+    assert_eq!(Ordering::Greater, compare("41.20240101.0", "40.20241023.0"));
+    assert_eq!(Ordering::Greater, compare("v1.10.0", "1.9.2"));
+    assert_eq!(Ordering::Equal, compare("v1.2.3", "1.2.3"));
+    assert!(is_downgrade("1.2.3", "1.2"));
+    assert!(!is_downgrade("1.2.3", "1.2.3"));
+    // Pre-releases come before their release.
+    assert_eq!(Ordering::Less, compare("v1.2.3-rc1", "1.2.3"));
+    assert_eq!(Ordering::Less, compare("1.2.3-rc.2", "1.2.3-rc.10"));
+    assert_eq!(Ordering::Greater, compare("1.2.4-rc1", "1.2.3"));
+    assert_eq!(Ordering::Equal, compare("1.2.3+build.5", "1.2.3"));
+    assert!(!is_downgrade("1.2.3-rc1", "1.2.3"));
+    assert!(is_downgrade("1.2.3", "1.2.3-rc1"));
+
+    let mock_server = MockServer::start().await;
+    Mock::given(method("GET"))
+        .and(path("/brog.yaml"))
+        .respond_with(
+            ResponseTemplate::new(200)
+                .set_body_string("clientConfig:\n  image: quay.io/mehal_tech/clos:v0.0.5\n"),
+        )
+        .mount(&mock_server)
+        .await;
+    Mock::given(method("GET"))
+        .and(path("/allowed.yaml"))
+        .respond_with(ResponseTemplate::new(200).set_body_string(
+            "clientConfig:\n  image: quay.io/mehal_tech/clos:v0.0.5\n  allowDowngrade: true\n",
+        ))
+        .mount(&mock_server)
+        .await;
+    Mock::given(method("GET"))
+        .and(path("/older.yaml"))
+        .respond_with(ResponseTemplate::new(200).set_body_string(
+            "clientConfig:\n  image: quay.io/mehal_tech/clos:v0.0.4\n  resolveDigest: true\n",
+        ))
+        .mount(&mock_server)
+        .await;
+    Mock::given(method("GET"))
+        .and(path("/unresolved.yaml"))
+        .respond_with(
+            ResponseTemplate::new(200)
+                .set_body_string("clientConfig:\n  image: quay.io/mehal_tech/clos:v0.0.4\n"),
+        )
+        .mount(&mock_server)
+        .await;
+    let mut bootcpath = env::current_dir().unwrap_or_default();
+    bootcpath.push(Path::new("mocks/rollback"));
+    let (_tmp, dir) = state_dir();
+    let run = |name: &str| {
+        reconcile(test_config(
+            &format!("{}/{}", mock_server.uri(), name),
+            bootcpath.to_str().unwrap(),
+            &dir,
+        ))
+    };
+
+    let outcome = run("brog.yaml").await.unwrap();
+    match &outcome.outcome {
+        Outcome::Rejected { policy, reason } => {
+            assert_eq!("allowDowngrade", policy);
+            assert!(reason
+                .starts_with("Refusing to downgrade from version 40.20241023.0 to 40.20240901.0"));
+        }
+        outcome => panic!("unexpected outcome {:?}", outcome),
+    }
+    let state = StateStore::new(&dir).load().unwrap();
+    assert_eq!("downgradeRefused", state.history.last().unwrap().event);
+
+    let plan = run("allowed.yaml").await.unwrap().plan();
+    assert!(plan.switch);
+    let state = StateStore::new(&dir).load().unwrap();
+    assert_eq!("switched", state.history.last().unwrap().event);
+
+    // bootc does not know v0.0.4, its version is read from the registry.
+    let labelled = |name: &str, version: &'static str| {
+        let endpoint = format!("{}/{}", mock_server.uri(), name);
+        let config = test_config(&endpoint, bootcpath.to_str().unwrap(), &dir)
+            .build()
+            .unwrap();
+        let agent = Agent::new(config)
+            .unwrap()
+            .with_registry(Arc::new(LabelRegistry(version)));
+        async move { agent.reconcile().await.unwrap() }
+    };
+    let outcome = labelled("older.yaml", "40.20240801.0").await;
+    assert!(
+        matches!(&outcome.outcome, Outcome::Rejected { policy, .. } if policy == "allowDowngrade"),
+        "{:?}",
+        outcome
+    );
+    assert!(labelled("older.yaml", "41.20250101.0").await.is_change());
+    // Without resolveDigest the registry is not asked.
+    assert!(labelled("unresolved.yaml", "40.20240801.0")
+        .await
+        .is_change());
+}
+
+#[tokio::test]
+async fn test_registry_resolves_tag_with_token() {
+    use brog::image::ImageRef;
+    use brog::registry::{HttpRegistryClient, RegistryClient};
+    use wiremock::matchers::{header, method, path, query_param};
+    use wiremock::{Mock, MockServer, ResponseTemplate};
+
+    let mock_server = MockServer::start().await;
+    Mock::given(method("HEAD"))
+        .and(path("/v2/fedora/fedora-bootc/manifests/41"))
+        .and(header("authorization", "Bearer pull-token"))
+        .respond_with(ResponseTemplate::new(200).append_header(
+            "docker-content-digest",
+            "sha256:0b5a7f1c0b1b4b0d8c63a9c3a1ab4c1f9c1b1c5f3e1e2d6b7a8c9d0e1f2a3b4c",
+        ))
+        .with_priority(1)
+        .mount(&mock_server)
+        .await;
+    Mock::given(method("HEAD"))
+        .and(path("/v2/fedora/fedora-bootc/manifests/41"))
+        .respond_with(ResponseTemplate::new(401).append_header(
+            "www-authenticate",
+            format!(
+                "Bearer realm=\"{}/token\",service=\"registry\",scope=\"repository:fedora/fedora-bootc:pull\"",
+                mock_server.uri()
+            )
+            .as_str(),
+        ))
+        .with_priority(2)
+        .mount(&mock_server)
+        .await;
+    Mock::given(method("GET"))
+        .and(path("/token"))
+        .and(query_param("service", "registry"))
+        .and(query_param("scope", "repository:fedora/fedora-bootc:pull"))
+        .respond_with(ResponseTemplate::new(200).set_body_string("{\"token\":\"pull-token\"}"))
+        .expect(1)
+        .mount(&mock_server)
+        .await;
+
+    let address = mock_server.address();
+    let image = ImageRef::parse(&format!("{}/fedora/fedora-bootc:41", address)).unwrap();
+    let digest = HttpRegistryClient::default().resolve(&image).await.unwrap();
+    assert_eq!(
+        "sha256:0b5a7f1c0b1b4b0d8c63a9c3a1ab4c1f9c1b1c5f3e1e2d6b7a8c9d0e1f2a3b4c",
+        digest
+    );
+    let missing = ImageRef::parse(&format!("{}/fedora/fedora-bootc:40", address)).unwrap();
+    assert!(HttpRegistryClient::default()
+        .resolve(&missing)
+        .await
+        .is_err());
+}
+
+#[tokio::test]
+async fn test_registry_reads_version_label() {
+    use brog::image::ImageRef;
+    use brog::registry::{HttpRegistryClient, RegistryClient};
+    use wiremock::matchers::{method, path, path_regex};
+    use wiremock::{Mock, MockServer, ResponseTemplate};
+
+    let mock_server = MockServer::start().await;
+    Mock::given(method("GET"))
+        .and(path("/v2/os/manifests/41"))
+        .respond_with(ResponseTemplate::new(200).set_body_json(serde_json::json!({
+            "mediaType": "application/vnd.oci.image.index.v1+json",
+            "manifests": [
+                {"digest": "sha256:a1", "platform": {"os": "linux", "architecture": "amd64"}},
+                {"digest": "sha256:a2", "platform": {"os": "linux", "architecture": "arm64"}},
+            ],
+        })))
+        .mount(&mock_server)
+        .await;
+    Mock::given(method("GET"))
+        .and(path_regex("^/v2/os/manifests/sha256:a[12]$"))
+        .respond_with(ResponseTemplate::new(200).set_body_json(serde_json::json!({
+            "mediaType": "application/vnd.oci.image.manifest.v1+json",
+            "config": {"digest": "sha256:c1"},
+        })))
+        .mount(&mock_server)
+        .await;
+    Mock::given(method("GET"))
+        .and(path("/v2/os/blobs/sha256:c1"))
+        .respond_with(ResponseTemplate::new(200).set_body_json(serde_json::json!({
+            "config": {"Labels": {"org.opencontainers.image.version": "41.20250101.0"}},
+        })))
+        .mount(&mock_server)
+        .await;
+    Mock::given(method("GET"))
+        .and(path("/v2/os/manifests/unlabelled"))
+        .respond_with(ResponseTemplate::new(200).set_body_json(serde_json::json!({
+            "config": {"digest": "sha256:c2"},
+        })))
+        .mount(&mock_server)
+        .await;
+    Mock::given(method("GET"))
+        .and(path("/v2/os/blobs/sha256:c2"))
+        .respond_with(ResponseTemplate::new(200).set_body_json(serde_json::json!({
+            "config": {},
+        })))
+        .mount(&mock_server)
+        .await;
+
+    let address = mock_server.address();
+    let registry = HttpRegistryClient::default();
+    let image = ImageRef::parse(&format!("{}/os:41", address)).unwrap();
+    assert_eq!(
+        Some("41.20250101.0".to_owned()),
+        registry.version(&image).await.unwrap()
+    );
+    let image = ImageRef::parse(&format!("{}/os:unlabelled", address)).unwrap();
+    assert_eq!(None, registry.version(&image).await.unwrap());
+    let image = ImageRef::parse(&format!("{}/os:40", address)).unwrap();
+    assert_eq!(
+        Some(404),
+        registry.version(&image).await.unwrap_err().status()
+    );
+}
+
+#[derive(Debug)]
+struct FakeRegistry(String);
+
+#[async_trait::async_trait]
+impl brog::registry::RegistryClient for FakeRegistry {
+    async fn resolve(&self, _image: &brog::image::ImageRef) -> Result<String, BrogError> {
+        Ok(self.0.clone())
+    }
+}
+
+/// A registry that labels every image with the version in `.0`.
+#[derive(Debug)]
+struct LabelRegistry(&'static str);
+
+#[async_trait::async_trait]
+impl brog::registry::RegistryClient for LabelRegistry {
+    async fn resolve(&self, _image: &brog::image::ImageRef) -> Result<String, BrogError> {
+        Ok("sha256:04".to_owned())
+    }
+
+    async fn version(&self, _image: &brog::image::ImageRef) -> Result<Option<String>, BrogError> {
+        Ok(Some(self.0.to_owned()))
+    }
+}
+
+#[tokio::test]
+async fn test_moved_tag_triggers_upgrade() {
+    use brog::StateStore;
+    use std::path::Path;
+    use std::sync::Arc;
+    use wiremock::matchers::method;
+    use wiremock::{Mock, MockServer, ResponseTemplate};
+
+    let mock_server = MockServer::start().await;
+    Mock::given(method("GET"))
+        .respond_with(ResponseTemplate::new(200).set_body_string(
+            "clientConfig:\n  image: quay.io/mehal_tech/clos:v0.0.6\n  resolveDigest: true\n",
+        ))
+        .mount(&mock_server)
+        .await;
+    let mut bootcpath = env::current_dir().unwrap_or_default();
+    bootcpath.push(Path::new("mocks"));
+    let (_tmp, dir) = state_dir();
+    let run = |digest: &str| {
+        let config = test_config(&mock_server.uri(), bootcpath.to_str().unwrap(), &dir)
+            .build()
+            .unwrap();
+        let agent = Agent::new(config)
+            .unwrap()
+            .with_registry(Arc::new(FakeRegistry(digest.to_owned())));
+        async move { agent.reconcile().await.map(|outcome| outcome.plan()) }
+    };
+
+    let plan = run("sha256:ed3e3f40c0db29da93bd7f805640e9f5c5b1aec5d80fbfe29250485260aa8cdf")
+        .await
+        .unwrap();
+    assert!(!plan.switch);
+    assert_eq!("already booted", plan.reason);
+
+    let moved = "sha256:0b5a7f1c0b1b4b0d8c63a9c3a1ab4c1f9c1b1c5f3e1e2d6b7a8c9d0e1f2a3b4c";
+    let plan = run(moved).await.unwrap();
+    assert!(plan.switch);
+    assert_eq!(format!("tag moved to {}", moved), plan.reason);
+    let state = StateStore::new(&dir).load().unwrap();
+    assert_eq!("upgraded", state.history.last().unwrap().event);
+    assert_eq!(Some(moved.to_owned()), state.applied_digest);
+}
+
+#[tokio::test]
+async fn test_consecutive_tag_moves_are_not_downgrades() {
+    use brog::bootc::{ImageReference, ImageStatus};
+    use brog::{BootcDriver, FakeDriver, Outcome};
+    use std::sync::Arc;
+    use wiremock::matchers::method;
+    use wiremock::{Mock, MockServer, ResponseTemplate};
+
+    let image = "quay.io/mehal_tech/clos:41";
+    let mock_server = MockServer::start().await;
+    Mock::given(method("GET"))
+        .respond_with(ResponseTemplate::new(200).set_body_string(format!(
+            "clientConfig:\n  image: {}\n  updatePolicy: upgrade\n",
+            image
+        )))
+        .mount(&mock_server)
+        .await;
+    let status = |version: &str, digest: &str| ImageStatus {
+        image: ImageReference {
+            image: image.to_owned(),
+            transport: "registry".to_owned(),
+        },
+        version: Some(version.to_owned()),
+        image_digest: digest.to_owned(),
+        ..Default::default()
+    };
+    let mut host = FakeDriver::booted(image, "sha256:01").host();
+    host.status.booted.as_mut().unwrap().image = Some(status("41.1", "sha256:01"));
+    let fake = Arc::new(FakeDriver::new(host));
+    let (_tmp, dir) = state_dir();
+
+    // After the first upgrade the rollback deployment is the same tag at an
+    // older version, which must not be taken for the next target.
+    for (version, digest) in [("41.2", "sha256:02"), ("41.3", "sha256:03")] {
+        fake.set_cached_update(status(version, digest));
+        let config = test_config(&mock_server.uri(), "/nonexistent", &dir)
+            .build()
+            .unwrap();
+        let outcome = Agent::new(config)
+            .unwrap()
+            .with_driver(fake.clone())
+            .reconcile()
+            .await
+            .unwrap();
+        assert_eq!(Outcome::Applied, outcome.outcome, "{}", outcome.reason);
+        let host = fake.status().await.unwrap();
+        assert_eq!(digest, host.booted_image().unwrap().image_digest);
+    }
+    assert_eq!(
+        "41.2",
+        fake.host()
+            .rollback_image()
+            .unwrap()
+            .version
+            .as_deref()
+            .unwrap()
+    );
+}
+
+#[tokio::test]
+async fn test_tag_moved_after_staging_is_checked() {
+    use brog::bootc::{ImageReference, ImageStatus};
+    use brog::{BootcDriver, FakeDriver, Outcome};
+    use std::sync::Arc;
+    use wiremock::matchers::method;
+    use wiremock::{Mock, MockServer, ResponseTemplate};
+
+    let image = "quay.io/mehal_tech/clos:v0.0.6";
+    let mock_server = MockServer::start().await;
+    Mock::given(method("GET"))
+        .respond_with(
+            ResponseTemplate::new(200)
+                .set_body_string(format!("clientConfig:\n  image: {}\n", image)),
+        )
+        .mount(&mock_server)
+        .await;
+    let status = |version: &str, digest: &str| ImageStatus {
+        image: ImageReference {
+            image: image.to_owned(),
+            transport: "registry".to_owned(),
+        },
+        version: Some(version.to_owned()),
+        image_digest: digest.to_owned(),
+        ..Default::default()
+    };
+    let mut host = FakeDriver::booted("quay.io/mehal_tech/clos:v0.0.5", "sha256:05").host();
+    if let Some(booted) = host.status.booted.as_mut().and_then(|b| b.image.as_mut()) {
+        booted.version = Some("40.5".to_owned());
+    }
+    let fake = Arc::new(FakeDriver::new(host).with_digest(image, "sha256:06"));
+    let (_tmp, dir) = state_dir();
+    let agent = |stage_only: bool| {
+        let config = test_config(&mock_server.uri(), "/nonexistent", &dir)
+            .stage_only(stage_only)
+            .build()
+            .unwrap();
+        Agent::new(config).unwrap().with_driver(fake.clone())
+    };
+    let outcome = agent(true).reconcile().await.unwrap();
+    assert_eq!(Outcome::Staged, outcome.outcome);
+    assert_eq!(
+        "sha256:06",
+        fake.host().staged_image().unwrap().image_digest
+    );
+
+    // The tag now points at older content, which bootc upgrade would boot.
+    fake.set_cached_update(status("40.4", "sha256:04"));
+    let err = agent(true).apply_staged().await.unwrap_err();
+    assert!(
+        matches!(&err, BrogError::NotStaged { target: Some(target), .. } if target.ends_with("@sha256:04")),
+        "{:?}",
+        err
+    );
+    let outcome = agent(false).reconcile().await.unwrap();
+    assert!(
+        matches!(&outcome.outcome, Outcome::Rejected { policy, .. } if policy == "allowDowngrade"),
+        "{:?}",
+        outcome
+    );
+    assert!(!fake.calls().iter().any(|call| call.contains("--apply")));
+
+    fake.set_cached_update(status("40.7", "sha256:07"));
+    let outcome = agent(false).reconcile().await.unwrap();
+    assert_eq!(Outcome::Applied, outcome.outcome, "{}", outcome.reason);
+    assert_eq!("tag moved to sha256:07", outcome.reason);
+    let host = fake.status().await.unwrap();
+    assert_eq!("sha256:07", host.booted_image().unwrap().image_digest);
+}
+
+#[tokio::test]
+async fn test_update_policy() {
+    use brog::config::UpdatePolicy;
+    use brog::{registry::RegistryClient, StateStore};
+    use std::path::Path;
+    use std::sync::Arc;
+    use wiremock::matchers::{method, path};
+    use wiremock::{Mock, MockServer, ResponseTemplate};
+
+    let mock_server = MockServer::start().await;
+    for policy in ["switch", "upgrade", "pinned"] {
+        Mock::given(method("GET"))
+            .and(path(format!("/{}.yaml", policy)))
+            .respond_with(ResponseTemplate::new(200).set_body_string(format!(
+                "clientConfig:\n  image: quay.io/mehal_tech/clos:v0.0.6\n  updatePolicy: {}\n  resolveDigest: {}\n",
+                policy,
+                policy == "pinned"
+            )))
+            .mount(&mock_server)
+            .await;
+    }
+    let run = |policy: &str, dir: &str, registry: Option<Arc<dyn RegistryClient>>| {
+        let bootcpath = env::current_dir()
+            .unwrap_or_default()
+            .join(Path::new("mocks/update"));
+        let endpoint = format!("{}/{}.yaml", mock_server.uri(), policy);
+        let config = test_config(&endpoint, bootcpath.to_str().unwrap(), dir)
+            .build()
+            .unwrap();
+        let agent = Agent::new(config).unwrap();
+        let agent = match registry {
+            Some(registry) => agent.with_registry(registry),
+            None => agent,
+        };
+        async move { agent.reconcile().await.map(|outcome| outcome.plan()) }
+    };
+    let cached = "sha256:9c2e4a6b8d0f1e3c5a7b9d1f3e5c7a9b1d3f5e7c9a1b3d5f7e9c1a3b5d7f9e1c";
+
+    let (_tmp, dir) = state_dir();
+    let plan = run("switch", &dir, None).await.unwrap();
+    assert!(!plan.switch);
+
+    let (_tmp, dir) = state_dir();
+    let plan = run("upgrade", &dir, None).await.unwrap();
+    assert!(plan.switch);
+    assert_eq!(format!("tag moved to {}", cached), plan.reason);
+    let state = StateStore::new(&dir).load().unwrap();
+    assert_eq!("upgraded", state.history.last().unwrap().event);
+
+    // Pinned ignores a moved tag even when the registry reports it.
+    let (_tmp, dir) = state_dir();
+    let registry = Arc::new(FakeRegistry(cached.to_owned()));
+    let plan = run("pinned", &dir, Some(registry)).await.unwrap();
+    assert!(!plan.switch);
+    assert_eq!("already booted", plan.reason);
+
+    assert!(brog::BrogDocument::from_yaml(
+        "clientConfig:\n  image: quay.io/mehal_tech/clos:v0.0.6\n  updatePolicy: sometimes\n"
+    )
+    .is_err());
+    assert_eq!(UpdatePolicy::Switch, UpdatePolicy::default());
+}
+
+#[tokio::test]
+async fn test_command_driver_timeout() {
+    use brog::{BootcDriver, CommandDriver, CommandError};
+    use std::time::{Duration, Instant};
+
+    let path = env::current_dir().unwrap_or_default().join("mocks/slow");
+    let driver =
+        CommandDriver::new(path.to_str().unwrap()).with_timeout(Duration::from_millis(200));
+    let started = Instant::now();
+    let err = driver
+        .switch("quay.io/fedora/fedora-bootc:41", true)
+        .await
+        .unwrap_err();
+    assert!(started.elapsed() < Duration::from_secs(10));
+    assert!(matches!(
+        err,
+        BrogError::Command(CommandError::TimedOut { .. })
+    ));
+
+    let path = env::current_dir().unwrap_or_default().join("mocks/warning");
+    let driver = CommandDriver::new(path.to_str().unwrap());
+    let output = driver
+        .upgrade(brog::driver::UpgradeMode::Apply)
+        .await
+        .unwrap();
+    assert_eq!(output.stdout.trim(), "bootc upgrade --apply");
+}
+
+#[tokio::test]
+async fn test_fake_driver_reconcile() {
+    use brog::{BootcDriver, FakeDriver, StateStore};
+    use std::sync::Arc;
+    use wiremock::matchers::{method, path};
+    use wiremock::{Mock, MockServer, ResponseTemplate};
+
+    let mock_server = MockServer::start().await;
+    Mock::given(method("GET"))
+        .and(path("/brog.yaml"))
+        .respond_with(ResponseTemplate::new(200).set_body_string(
+            "clientConfig:\n  image: quay.io/mehal_tech/clos:v0.0.6\n  retrycount: 1\n",
+        ))
+        .mount(&mock_server)
+        .await;
+    let (_tmp, dir) = state_dir();
+    let fake = Arc::new(
+        FakeDriver::booted("quay.io/mehal_tech/clos:v0.0.5", "sha256:05")
+            .with_digest("quay.io/mehal_tech/clos:v0.0.6", "sha256:06")
+            .with_kargs(&["quiet", "rw"]),
+    );
+    fake.fail_next("error: Pulling: connection reset");
+    let config = test_config(
+        &format!("{}/brog.yaml", mock_server.uri()),
+        "/nonexistent",
+        &dir,
+    )
+    .build()
+    .unwrap();
+    let plan = Agent::new(config)
+        .unwrap()
+        .with_driver(fake.clone())
+        .reconcile()
+        .await
+        .unwrap()
+        .plan();
+    assert!(plan.switch && plan.apply);
+    assert_eq!(
+        vec![
+            "switch quay.io/mehal_tech/clos:v0.0.6 --apply",
+            "switch quay.io/mehal_tech/clos:v0.0.6 --apply"
+        ],
+        fake.calls()
+    );
+    let host = fake.status().await.unwrap();
+    assert_eq!("sha256:06", host.booted_image().unwrap().image_digest);
+    assert_eq!(
+        "quay.io/mehal_tech/clos:v0.0.5",
+        host.rollback_image().unwrap().image.image
+    );
+    assert_eq!(vec!["quiet", "rw"], fake.kargs().await.unwrap());
+    let state = StateStore::new(&dir).load().unwrap();
+    assert_eq!("switched", state.history.last().unwrap().event);
+
+    fake.rollback(true).await.unwrap();
+    let host = fake.status().await.unwrap();
+    assert_eq!(
+        "quay.io/mehal_tech/clos:v0.0.5",
+        host.booted_image().unwrap().image.image
+    );
+}
+
+#[tokio::test]
+async fn test_brog_config() {
+    use brog::BrogConfig;
+    use std::time::Duration;
+
+    let (_tmp, dir) = state_dir();
+    let file = format!("{}/config", dir);
+    std::fs::write(
+        &file,
+        "# brog settings\nENDPOINT=\"https://example.com/brog.yaml\"\nSERVICE_KEY=key\nSERVICE_SECRET='topsecret'\nFETCH_RETRIES=2\nBOOTC_TIMEOUT=90\nexport DRY_RUN=true\nMAINTENANCE_WINDOWS=Mon-Fri 02:00-04:00 Europe/Berlin\n",
+    )
+    .unwrap();
+    let config = BrogConfig::from_file(std::path::Path::new(&file)).unwrap();
+    assert_eq!("https://example.com/brog.yaml", config.endpoint);
+    assert_eq!("topsecret", config.service_secret);
+    assert_eq!("projects", config.service_name);
+    assert_eq!(3, config.fetch_retry.max_attempts);
+    assert_eq!(Duration::from_secs(90), config.bootc_timeout);
+    assert!(config.dry_run && !config.stage_only);
+    assert_eq!(1, config.maintenance_windows.as_ref().unwrap().len());
+    let debug = format!("{:?}", config);
+    assert!(!debug.contains("topsecret"));
+    assert!(debug.contains("<redacted>"));
+
+    std::fs::write(&file, "FETCH_TIMEOUT=soon\n").unwrap();
+    let err = BrogConfig::from_file(std::path::Path::new(&file)).unwrap_err();
+    assert!(err.to_string().contains("FETCH_TIMEOUT must be a number"));
+    for retries in ["4294967295", "4294967296"] {
+        std::fs::write(&file, format!("FETCH_RETRIES={}\n", retries)).unwrap();
+        let err = BrogConfig::from_file(std::path::Path::new(&file)).unwrap_err();
+        assert!(matches!(err, BrogError::Config { .. }), "{:?}", err);
+    }
+
+    assert!(BrogConfig::builder("not a url").build().is_err());
+    assert!(BrogConfig::builder("https://example.com/brog.yaml")
+        .service_secret("secret")
+        .build()
+        .is_err());
+    assert!(BrogConfig::builder("https://example.com/brog.yaml")
+        .fetch_timeout(Duration::ZERO)
+        .build()
+        .is_err());
+}
+
+#[tokio::test]
+async fn test_agent_reconciles_with_config() {
+    use brog::{Agent, BrogConfig, FakeDriver, Outcome, StateStore};
+    use std::sync::Arc;
+    use wiremock::matchers::{header, method, path};
+    use wiremock::{Mock, MockServer, ResponseTemplate};
+
+    let mock_server = MockServer::start().await;
+    Mock::given(method("GET"))
+        .and(path("/brog.yaml"))
+        .and(header("x-mhl-mid", "0123456789abcdef"))
+        .and(header("x-mhl-hostname", "agent-test"))
+        .respond_with(
+            ResponseTemplate::new(200)
+                .set_body_string("clientConfig:\n  image: quay.io/mehal_tech/clos:v0.0.6\n"),
+        )
+        .mount(&mock_server)
+        .await;
+    let (_tmp, dir) = state_dir();
+    std::fs::write(format!("{}/machine-id", dir), "0123456789abcdef\n").unwrap();
+    std::fs::write(format!("{}/hostname", dir), "agent-test\n").unwrap();
+    let config = BrogConfig::builder(&format!("{}/brog.yaml", mock_server.uri()))
+        .service_key("key")
+        .service_secret("secret")
+        .config_path(&dir)
+        .machine_id_path(&format!("{}/machine-id", dir))
+        .hostname_path(&format!("{}/hostname", dir))
+        .policy_path(None)
+        .build()
+        .unwrap();
+    let fake = Arc::new(FakeDriver::booted(
+        "quay.io/mehal_tech/clos:v0.0.5",
+        "sha256:05",
+    ));
+    let agent = Agent::new(config).unwrap().with_driver(fake.clone());
+    let outcome = agent.reconcile().await.unwrap();
+    assert_eq!(Outcome::Applied, outcome.outcome);
+    assert_eq!(
+        Some("quay.io/mehal_tech/clos:v0.0.5@sha256:05"),
+        outcome.previous_image.as_deref()
+    );
+    assert_eq!(
+        vec!["switch quay.io/mehal_tech/clos:v0.0.6 --apply"],
+        fake.calls()
+    );
+    // The same agent finds the host up to date on the next tick.
+    let outcome = agent.reconcile().await.unwrap();
+    assert_eq!(Outcome::NoChange, outcome.outcome);
+    assert_eq!("already booted", outcome.reason);
+    let state = StateStore::new(&dir).load().unwrap();
+    assert!(state.last_success.is_some());
+}
+
+#[tokio::test]
+async fn test_agent_skips_overlapping_ticks() {
+    use brog::{Agent, BrogConfig, FakeDriver};
+    use std::sync::Arc;
+    use std::time::Duration;
+    use wiremock::matchers::method;
+    use wiremock::{Mock, MockServer, ResponseTemplate};
+
+    let mock_server = MockServer::start().await;
+    Mock::given(method("GET"))
+        .respond_with(
+            ResponseTemplate::new(200)
+                .set_body_string("clientConfig:\n  image: quay.io/mehal_tech/clos:v0.0.6\n")
+                .set_delay(Duration::from_millis(500)),
+        )
+        .mount(&mock_server)
+        .await;
+    let (_tmp, dir) = state_dir();
+    std::fs::write(format!("{}/machine-id", dir), "0123456789abcdef\n").unwrap();
+    std::fs::write(format!("{}/hostname", dir), "overlap-test\n").unwrap();
+    let config = BrogConfig::builder(&format!("{}/brog.yaml", mock_server.uri()))
+        .config_path(&dir)
+        .machine_id_path(&format!("{}/machine-id", dir))
+        .hostname_path(&format!("{}/hostname", dir))
+        .policy_path(None)
+        .build()
+        .unwrap();
+    let fake = Arc::new(FakeDriver::booted(
+        "quay.io/mehal_tech/clos:v0.0.5",
+        "sha256:05",
+    ));
+    let agent = Agent::new(config).unwrap().with_driver(fake.clone());
+
+    let running = tokio::spawn({
+        let agent = agent.clone();
+        async move { agent.reconcile().await }
+    });
+    tokio::time::sleep(Duration::from_millis(100)).await;
+    assert!(agent.try_reconcile().await.unwrap().is_none());
+    assert!(running.await.unwrap().unwrap().is_change());
+    assert!(agent.try_reconcile().await.unwrap().is_some());
+    assert_eq!(
+        vec!["switch quay.io/mehal_tech/clos:v0.0.6 --apply"],
+        fake.calls()
+    );
+}
+
+#[tokio::test]
+async fn test_reconcile_outcomes() {
+    use brog::state::RollbackRecord;
+    use brog::{Agent, BrogConfig, FakeDriver, Outcome, StateStore};
+    use std::sync::Arc;
+    use wiremock::matchers::method;
+    use wiremock::{Mock, MockServer, ResponseTemplate};
+
+    let mock_server = MockServer::start().await;
+    Mock::given(method("GET"))
+        .respond_with(
+            ResponseTemplate::new(200)
+                .set_body_string("clientConfig:\n  image: quay.io/mehal_tech/clos:v0.0.6\n")
+                .insert_header("x-clos-commit", "c0ffee"),
+        )
+        .mount(&mock_server)
+        .await;
+    let (_tmp, dir) = state_dir();
+    std::fs::write(format!("{}/machine-id", dir), "0123456789abcdef\n").unwrap();
+    std::fs::write(format!("{}/hostname", dir), "outcome-test\n").unwrap();
+    let policy_path = format!("{}/policy.yaml", dir);
+    std::fs::write(&policy_path, "forbiddenTags: [v0.0.6]\n").unwrap();
+    let builder = || {
+        BrogConfig::builder(&format!("{}/brog.yaml", mock_server.uri()))
+            .config_path(&dir)
+            .machine_id_path(&format!("{}/machine-id", dir))
+            .hostname_path(&format!("{}/hostname", dir))
+            .policy_path(None)
+    };
+    let fake = Arc::new(FakeDriver::booted(
+        "quay.io/mehal_tech/clos:v0.0.5",
+        "sha256:05",
+    ));
+
+    let agent = Agent::new(builder().stage_only(true).build().unwrap())
+        .unwrap()
+        .with_driver(fake.clone());
+    let outcome = agent.reconcile().await.unwrap();
+    assert_eq!(Outcome::Staged, outcome.outcome);
+    assert_eq!("quay.io/mehal_tech/clos:v0.0.6", outcome.target_image);
+    assert_eq!(Some("c0ffee"), outcome.commit.as_deref());
+    assert!(outcome.is_change());
+    // The staged deployment waits for `brog apply`.
+    let outcome = agent.reconcile().await.unwrap();
+    assert_eq!(
+        Outcome::Deferred {
+            reason: "staged, waiting for brog apply".to_owned()
+        },
+        outcome.outcome
+    );
+    assert!(!outcome.is_change());
+
+    let agent = Agent::new(builder().policy_path(Some(&policy_path)).build().unwrap())
+        .unwrap()
+        .with_driver(fake.clone());
+    let outcome = agent.reconcile().await.unwrap();
+    assert_eq!(
+        Outcome::Rejected {
+            policy: "imagePolicy".to_owned(),
+            reason: "Image policy rejects quay.io/mehal_tech/clos:v0.0.6: tag v0.0.6 is forbidden"
+                .to_owned()
+        },
+        outcome.outcome
+    );
+    assert_eq!(
+        Some("quay.io/mehal_tech/clos:v0.0.5@sha256:05"),
+        outcome.previous_image.as_deref()
+    );
+    assert_eq!(vec!["switch quay.io/mehal_tech/clos:v0.0.6"], fake.calls());
+
+    // A target that was rolled back is refused rather than retried.
+    let store = StateStore::new(&dir);
+    let mut state = store.load().unwrap();
+    state.rollbacks.push(RollbackRecord {
+        image: "quay.io/mehal_tech/clos:v0.0.6".to_owned(),
+        digest: None,
+        reason: "failed health checks on 3 boots".to_owned(),
+        at: chrono::Utc::now(),
+    });
+    store.save(&state).unwrap();
+    let fake = Arc::new(FakeDriver::booted(
+        "quay.io/mehal_tech/clos:v0.0.5",
+        "sha256:05",
+    ));
+    let agent = Agent::new(builder().build().unwrap())
+        .unwrap()
+        .with_driver(fake.clone());
+    let outcome = agent.reconcile().await.unwrap();
+    assert_eq!(
+        Outcome::Rejected {
+            policy: "healthCheck".to_owned(),
+            reason: "it was rolled back after failing health checks".to_owned()
+        },
+        outcome.outcome
+    );
+    assert!(fake.calls().is_empty());
+}
+
+#[tokio::test]
+async fn test_metrics_endpoint() {
+    use brog::{metrics, retry::RetryPolicy, Agent, BrogConfig, FakeDriver, Metrics};
+    use std::sync::Arc;
+    use std::time::Duration;
+    use wiremock::matchers::method;
+    use wiremock::{Mock, MockServer, ResponseTemplate};
+
+    assert!(BrogConfig::builder("https://example.com/brog.yaml")
+        .metrics_addr("localhost")
+        .build()
+        .is_err());
+
+    let mock_server = MockServer::start().await;
+    Mock::given(method("GET"))
+        .respond_with(ResponseTemplate::new(503))
+        .up_to_n_times(1)
+        .mount(&mock_server)
+        .await;
+    Mock::given(method("GET"))
+        .respond_with(
+            ResponseTemplate::new(200)
+                .set_body_string("clientConfig:\n  image: quay.io/mehal_tech/clos:v0.0.6\n"),
+        )
+        .mount(&mock_server)
+        .await;
+    let (_tmp, dir) = state_dir();
+    std::fs::write(format!("{}/machine-id", dir), "0123456789abcdef\n").unwrap();
+    std::fs::write(format!("{}/hostname", dir), "metrics-test\n").unwrap();
+    let config = BrogConfig::builder(&format!("{}/brog.yaml", mock_server.uri()))
+        .config_path(&dir)
+        .machine_id_path(&format!("{}/machine-id", dir))
+        .hostname_path(&format!("{}/hostname", dir))
+        .policy_path(None)
+        .fetch_retry(RetryPolicy {
+            max_attempts: 2,
+            base_delay: Duration::from_millis(10),
+            max_delay: Duration::from_millis(50),
+        })
+        .build()
+        .unwrap();
+    let fake = Arc::new(FakeDriver::booted(
+        "quay.io/mehal_tech/clos:v0.0.5",
+        "sha256:05",
+    ));
+    let metrics = Arc::new(Metrics::default());
+    let agent = Agent::new(config)
+        .unwrap()
+        .with_driver(fake)
+        .with_metrics(metrics.clone());
+    agent.reconcile().await.unwrap();
+
+    let addr = metrics::listen("127.0.0.1:0", metrics).await.unwrap();
+    let body = reqwest::get(format!("http://{}/metrics", addr))
+        .await
+        .unwrap()
+        .text()
+        .await
+        .unwrap();
+    for line in [
+        "brog_fetch_attempts_total 2",
+        "brog_fetch_failures_total{reason=\"status\"} 1",
+        "brog_fetch_responses_total{status=\"200\"} 1",
+        "brog_fetch_responses_total{status=\"503\"} 1",
+        "brog_bootc_command_duration_seconds_count{command=\"switch\",result=\"success\"} 1",
+        "brog_reconciles_total{outcome=\"applied\"} 1",
+        "brog_rollbacks_total 0",
+        "brog_image_info{booted_image=\"quay.io/mehal_tech/clos:v0.0.6\",booted_digest=\"\",target_image=\"quay.io/mehal_tech/clos:v0.0.6\"} 1",
+        "brog_image_current 1",
+    ] {
+        assert!(body.lines().any(|l| l == line), "{} not in\n{}", line, body);
+    }
+    assert!(body.contains("brog_last_success_timestamp_seconds "));
+    let res = reqwest::get(format!("http://{}/", addr)).await.unwrap();
+    assert_eq!(404, res.status().as_u16());
+}
+
+#[tokio::test]
+async fn test_brog_error_kinds() {
+    use brog::{retry::RetryPolicy, Outcome};
+    use std::path::Path;
+    use wiremock::matchers::{method, path};
+    use wiremock::{Mock, MockServer, ResponseTemplate};
+
+    let mock_server = MockServer::start().await;
+    for (route, response) in [
+        ("/busy.yaml", ResponseTemplate::new(503)),
+        ("/missing.yaml", ResponseTemplate::new(404)),
+        (
+            "/broken.yaml",
+            ResponseTemplate::new(200).set_body_string("clientConfig: [image"),
+        ),
+        (
+            "/empty.yaml",
+            ResponseTemplate::new(200).set_body_string("clientConfig: []\n"),
+        ),
+        (
+            "/latest.yaml",
+            ResponseTemplate::new(200)
+                .set_body_string("clientConfig:\n  image: quay.io/fedora/fedora-bootc:latest\n"),
+        ),
+        (
+            "/fedora.yaml",
+            ResponseTemplate::new(200)
+                .set_body_string("clientConfig:\n  image: quay.io/fedora/fedora-bootc:41\n"),
+        ),
+    ] {
+        Mock::given(method("GET"))
+            .and(path(route))
+            .respond_with(response)
+            .mount(&mock_server)
+            .await;
+    }
+    let (_tmp, dir) = state_dir();
+    std::fs::write(format!("{}/policy.yaml", dir), "forbiddenTags: [latest]\n").unwrap();
+    let policy_path = format!("{}/policy.yaml", dir);
+    let config = |ep: String, mock: &str| {
+        let bootcpath = env::current_dir().unwrap_or_default().join(Path::new(mock));
+        test_config(&ep, bootcpath.to_str().unwrap(), &dir)
+            .fetch_retry(RetryPolicy {
+                max_attempts: 1,
+                ..RetryPolicy::default()
+            })
+            .policy_path(Some(&policy_path))
+    };
+    let run = |ep: String, mock: &str| {
+        let config = config(ep, mock);
+        async move { reconcile(config).await.unwrap_err() }
+    };
+    let uri = mock_server.uri();
+
+    let err = run(format!("{}/busy.yaml", uri), "mocks").await;
+    assert_eq!(Some(503), err.status());
+    assert!(err.is_retryable());
+    let err = run(format!("{}/missing.yaml", uri), "mocks").await;
+    assert_eq!(Some(404), err.status());
+    assert!(!err.is_retryable());
+    let err = run("http://127.0.0.1:1/brog.yaml".to_owned(), "mocks").await;
+    assert!(matches!(err, BrogError::Transport { .. }), "{:?}", err);
+    assert!(err.is_retryable());
+    let err = run(format!("{}/broken.yaml", uri), "mocks").await;
+    assert!(
+        matches!(&err, BrogError::Parse { what, .. } if what == "brog.yaml"),
+        "{:?}",
+        err
+    );
+    let err = run(format!("{}/empty.yaml", uri), "mocks").await;
+    assert!(matches!(err, BrogError::Config { .. }), "{:?}", err);
+    let outcome = reconcile(config(format!("{}/latest.yaml", uri), "mocks"))
+        .await
+        .unwrap();
+    assert_eq!("quay.io/fedora/fedora-bootc:latest", outcome.target_image);
+    assert!(
+        matches!(&outcome.outcome, Outcome::Rejected { policy, .. } if policy == "imagePolicy"),
+        "{:?}",
+        outcome
+    );
+    let err = run(format!("{}/fedora.yaml", uri), "mocks/error").await;
+    assert!(matches!(err, BrogError::Command(_)), "{:?}", err);
+    assert!(!err.is_retryable());
+}
+
+#[tokio::test]
+async fn test_inspect_views() {
+    use brog::{inspect, FakeDriver, State, StateStore};
+    use wiremock::matchers::method;
+    use wiremock::{Mock, MockServer, ResponseTemplate};
+
+    let (_tmp, dir) = state_dir();
+    let mut state = State {
+        target_image: Some("quay.io/mehal_tech/clos:v0.0.6".to_owned()),
+        canary_percent: Some(25),
+        ..State::default()
+    };
+    state.record("switched", "quay.io/mehal_tech/clos:v0.0.6", None);
+    state.record(
+        "switchFailed",
+        "quay.io/mehal_tech/clos:v0.0.7",
+        Some("no space left".to_owned()),
+    );
+    StateStore::new(&dir).save(&state).unwrap();
+    let config = BrogConfig::builder("").config_path(&dir).build().unwrap();
+    let fake = FakeDriver::booted("quay.io/mehal_tech/clos:v0.0.5", "sha256:05");
+
+    let status = inspect::status(&config, &fake, false).await.unwrap();
+    let lines: Vec<&str> = status.lines().collect();
+    assert_eq!(
+        "booted        quay.io/mehal_tech/clos:v0.0.5@sha256:05",
+        lines[0]
+    );
+    assert_eq!("staged        -", lines[1]);
+    assert!(lines.contains(&"target        quay.io/mehal_tech/clos:v0.0.6"));
+    assert!(lines.contains(&"canary        25%"));
+    let status: serde_json::Value =
+        serde_json::from_str(&inspect::status(&config, &fake, true).await.unwrap()).unwrap();
+    assert_eq!(
+        "quay.io/mehal_tech/clos:v0.0.6",
+        status["state"]["targetImage"]
+    );
+    assert!(status["host"]["status"]["booted"].is_object());
+
+    let history = inspect::history(&config, false).unwrap();
+    let lines: Vec<&str> = history.lines().collect();
+    assert_eq!(2, lines.len());
+    assert!(lines[0].ends_with("switched     quay.io/mehal_tech/clos:v0.0.6"));
+    assert!(lines[1].ends_with("switchFailed quay.io/mehal_tech/clos:v0.0.7 (no space left)"));
+    let history: serde_json::Value =
+        serde_json::from_str(&inspect::history(&config, true).unwrap()).unwrap();
+    assert_eq!("switchFailed", history[1]["event"]);
+
+    let file = format!("{}/brog.yaml", dir);
+    std::fs::write(
+        &file,
+        "clientConfig:\n  image: quay.io/mehal_tech/clos:v0.0.6\n  colour: blue\n",
+    )
+    .unwrap();
+    let policy = format!("{}/policy.yaml", dir);
+    std::fs::write(&policy, "forbiddenTags: [v0.0.6]\n").unwrap();
+    let text = inspect::check_config(&file, None).await.unwrap();
+    assert_eq!(
+        format!(
+            "warning: unknown field clientConfig.colour\n{} is valid, image: quay.io/mehal_tech/clos:v0.0.6\n",
+            file
+        ),
+        text
+    );
+    let err = inspect::check_config(&file, Some(&policy))
+        .await
+        .unwrap_err();
+    assert!(matches!(err, BrogError::PolicyRejected { .. }), "{:?}", err);
+    let err = inspect::check_config(&format!("{}/missing.yaml", dir), None)
+        .await
+        .unwrap_err();
+    assert!(matches!(err, BrogError::Config { .. }), "{:?}", err);
+
+    let mock_server = MockServer::start().await;
+    Mock::given(method("GET"))
+        .respond_with(ResponseTemplate::new(404))
+        .mount(&mock_server)
+        .await;
+    let err = inspect::check_config(&format!("{}/brog.yaml", mock_server.uri()), None)
+        .await
+        .unwrap_err();
+    assert_eq!(Some(404), err.status());
 }